@@ -1,37 +1,988 @@
 #![allow(clippy::use_self)]
 
+use caseless::Caseless;
+use clap::ValueEnum;
+use color_eyre::eyre::anyhow;
+use color_eyre::Result;
+use regex::Regex;
+use regex::RegexBuilder;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::path::PathBuf;
+use unicode_normalization::UnicodeNormalization;
 
-pub type Configuration = Vec<Entry>;
+use crate::engine::Event;
+use crate::engine::WindowInfo;
+use crate::key_state::KeyState;
+use crate::resolve_windows_path;
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// The current version of the configuration format, written by `komokana migrate`
+pub const VERSION: u32 = 1;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Configuration {
+    /// The version of the configuration format, configurations without one are
+    /// treated as version 0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Connection settings for kanata's TCP server
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub kanata: Kanata,
+    /// Layer to default to when an active window doesn't match any rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_layer: Option<String>,
+    /// Milliseconds that focus has to rest on a window before its layer is
+    /// sent to kanata (default: 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settle_delay_ms: Option<u64>,
+    /// Where layer changes are published in addition to kanata
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub outputs: Outputs,
+    /// How lost connections to komorebi and kanata are retried
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub reconnect: Reconnect,
+    /// Pause automatic switching after the layer is changed by hand in kanata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manual_lock: Option<ManualLock>,
+    /// How to pick a layer when more than one rule matches a window (default: `last_match`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+    #[serde(default)]
+    pub rules: Vec<Entry>,
+    /// Test cases which can be run with `komokana test`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestCase>,
+    /// Other configuration files to load before this one, relative to this file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// The files which the configuration was loaded from, starting with
+    /// the file passed to [`load`] and followed by any included files
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+impl Configuration {
+    /// How to pick a layer when more than one rule matches a window
+    #[must_use]
+    pub fn resolution(&self) -> Resolution {
+        self.resolution.unwrap_or_default()
+    }
+
+    /// Compiles the regexes used by the rules, which have to be compiled
+    /// before the rules can match anything
+    pub(crate) fn compile(&mut self) -> Result<()> {
+        for entry in &mut self.rules {
+            entry
+                .compile()
+                .map_err(|error| anyhow!("entry for {}: {error}", entry.exe))?;
+        }
+
+        Ok(())
+    }
+
+    /// Every layer which the configuration refers to, along with a description
+    /// of the rule or test case which refers to it
+    #[must_use]
+    pub fn layers(&self) -> Vec<(String, &str)> {
+        let mut layers = vec![];
+
+        for (i, entry) in self.rules.iter().enumerate() {
+            let description = format!("entry {i} ({})", entry.exe);
+            layers.push((description.clone(), entry.target_layer.as_str()));
+
+            for class_override in entry.class_overrides.iter().flatten() {
+                layers.push((
+                    format!("{description} {class_override}"),
+                    &class_override.target_layer,
+                ));
+            }
+
+            for title_override in entry.title_overrides.iter().flatten() {
+                layers.push((
+                    format!("{description} {title_override}"),
+                    &title_override.target_layer,
+                ));
+            }
+
+            for virtual_key_override in entry.virtual_key_overrides.iter().flatten() {
+                layers.push((
+                    format!(
+                        "{description} key {}",
+                        virtual_key_override.virtual_key_code
+                    ),
+                    &virtual_key_override.target_layer,
+                ));
+            }
+        }
+
+        for case in &self.tests {
+            if let Some(expected_layer) = &case.expected_layer {
+                layers.push((format!("test case {case}"), expected_layer));
+            }
+        }
+
+        layers
+    }
+
+    /// Merges a configuration which was loaded after this one into it. Rules
+    /// in the later configuration replace any rules here for the same exe, and
+    /// its settings take precedence over the settings here.
+    fn merge(&mut self, later: Configuration) {
+        self.rules.retain(|entry| {
+            !later
+                .rules
+                .iter()
+                .any(|replacement| replacement.matches_exe(&entry.exe))
+        });
+
+        self.version = later.version.or(self.version);
+        self.kanata.port = later.kanata.port.or(self.kanata.port);
+        if later.default_layer.is_some() {
+            self.default_layer = later.default_layer;
+        }
+
+        self.settle_delay_ms = later.settle_delay_ms.or(self.settle_delay_ms);
+        self.outputs.tmpfile = later.outputs.tmpfile.or(self.outputs.tmpfile);
+        self.reconnect = later.reconnect.or(self.reconnect);
+        self.manual_lock = later.manual_lock.or(self.manual_lock);
+        self.resolution = later.resolution.or(self.resolution);
+        self.rules.extend(later.rules);
+        self.tests.extend(later.tests);
+        self.include = later.include;
+        self.files.extend(later.files);
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Kanata {
+    /// The port on which kanata's TCP server is running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<i32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Outputs {
+    /// Write the current layer to `~/AppData/Local/Temp/kanata_layer` (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmpfile: Option<bool>,
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Reconnect {
+    /// Milliseconds to wait before the first retry, doubling after every
+    /// failed attempt, and never less than 50
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_delay_ms: Option<u64>,
+    /// The longest that komokana will wait between attempts, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_delay_ms: Option<u64>,
+    /// Give up after this many failed attempts in a row, rather than retrying forever
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+}
+
+impl Reconnect {
+    /// Fills in any settings which aren't set here from `other`
+    #[must_use]
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            initial_delay_ms: self.initial_delay_ms.or(other.initial_delay_ms),
+            max_delay_ms: self.max_delay_ms.or(other.max_delay_ms),
+            max_attempts: self.max_attempts.or(other.max_attempts),
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ManualLock {
+    /// Milliseconds to pause automatic switching for, otherwise it is paused
+    /// until focus moves to a different window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+/// Settings which are left at their default values aren't written out
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// The file formats which a configuration can be written in
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Picks a format based on the file extension, falling back to YAML
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, raw: &str) -> Result<T> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(raw)?,
+            Format::Toml => toml::from_str(raw)?,
+            Format::Json => serde_json::from_str(raw)?,
+        })
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::to_string(value)?,
+            Format::Toml => toml::to_string_pretty(value)?,
+            Format::Json => serde_json::to_string_pretty(value)?,
+        })
+    }
+}
+
+/// Loads a configuration file along with any files that it includes
+pub fn load(path: &Path, format: Format) -> Result<Configuration> {
+    load_with_includes(path, format, &mut vec![])
+}
+
+fn load_with_includes(
+    path: &Path,
+    format: Format,
+    including: &mut Vec<PathBuf>,
+) -> Result<Configuration> {
+    if including.iter().any(|file| file == path) {
+        let cycle = including
+            .iter()
+            .map(|file| file.display().to_string())
+            .chain(std::iter::once(path.display().to_string()))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+
+        return Err(anyhow!("configuration files include each other: {cycle}"));
+    }
+
+    let raw = std::fs::read_to_string(path)?;
+    let mut configuration = parse(&raw, format)?;
+    configuration.files = vec![path.to_path_buf()];
+
+    for deprecation in deprecations(&format.deserialize(&raw)?) {
+        log::warn!(
+            "{}: {deprecation}, run `komokana migrate` to update your configuration",
+            path.display()
+        );
+    }
+
+    if configuration.include.is_empty() {
+        return Ok(configuration);
+    }
+
+    including.push(path.to_path_buf());
+
+    let mut merged = Configuration::default();
+    for include in &configuration.include {
+        let included = if include.starts_with('~') || Path::new(include).is_absolute() {
+            resolve_windows_path(include)?
+        } else {
+            let relative = path.parent().unwrap_or(path).join(include);
+            resolve_windows_path(&relative.to_string_lossy())?
+        };
+
+        merged.merge(
+            load_with_includes(&included, Format::from_path(&included), including)
+                .map_err(|error| anyhow!("{}: {error}", included.display()))?,
+        );
+    }
+
+    including.pop();
+
+    // The including file is merged last so that its rules and settings win,
+    // but it should still be listed first
+    let included_files = std::mem::take(&mut merged.files);
+    merged.merge(configuration);
+    merged.files.extend(included_files);
+
+    Ok(merged)
+}
+
+pub fn parse(raw: &str, format: Format) -> Result<Configuration> {
+    let value: serde_yaml::Value = format.deserialize(raw)?;
+
+    // The original configuration format was just a list of rules, which is
+    // upgraded to a configuration with default settings
+    let mut configuration = if value.is_sequence() {
+        Configuration {
+            rules: format.deserialize(raw)?,
+            ..Default::default()
+        }
+    } else {
+        format.deserialize(raw)?
+    };
+
+    if let Some(version) = configuration.version {
+        if version > VERSION {
+            return Err(anyhow!(
+                "configuration version {version} is newer than the latest version supported by this version of komokana ({VERSION})"
+            ));
+        }
+    }
+
+    configuration.compile()?;
+
+    if matches!(configuration.resolution(), Resolution::Priority) {
+        for (i, entry) in configuration.rules.iter().enumerate() {
+            for (j, later) in configuration.rules.iter().enumerate().skip(i + 1) {
+                let priority = entry.priority.unwrap_or(0);
+                if later.matches_exe(&entry.exe) && later.priority.unwrap_or(0) == priority {
+                    log::warn!(
+                        "entries {i} and {j} for {} both have priority {priority}, entry {j} will win when both match",
+                        entry.exe,
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(configuration)
+}
+
+/// The JSON Schema of the configuration file, accepting both the current
+/// format and the original list of rules
+#[must_use]
+pub fn schema() -> schemars::Schema {
+    schemars::schema_for!(ConfigurationFile)
+}
+
+#[derive(JsonSchema)]
+#[schemars(title = "komokana configuration", untagged)]
+#[allow(dead_code)]
+enum ConfigurationFile {
+    Configuration(Configuration),
+    Rules(Vec<Entry>),
+}
+
+/// Describes the deprecated keys in a configuration, which `komokana migrate` can update
+#[must_use]
+pub fn deprecations(value: &serde_yaml::Value) -> Vec<String> {
+    let mut deprecations = vec![];
+
+    if count_keys(value, "targer_layer") > 0 {
+        deprecations.push(String::from(
+            "targer_layer is deprecated in favour of target_layer",
+        ));
+    }
+
+    deprecations
+}
+
+fn count_keys(value: &serde_yaml::Value, key: &str) -> usize {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => mapping
+            .iter()
+            .map(|(k, v)| usize::from(k.as_str() == Some(key)) + count_keys(v, key))
+            .sum(),
+        serde_yaml::Value::Sequence(sequence) => sequence.iter().map(|v| count_keys(v, key)).sum(),
+        serde_yaml::Value::Tagged(tagged) => count_keys(&tagged.value, key),
+        _ => 0,
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// The first matching entry wins, and within it the first matching override.
+    /// Held key ignores and overrides of any matching entry win over both.
+    FirstMatch,
+    /// The last matching entry wins, and within it the last matching override.
+    /// Held key ignores and overrides only apply when they belong to that entry.
+    #[default]
+    LastMatch,
+    /// The matching entry or override with the highest priority wins, falling
+    /// back to the last match when priorities are equal. Held key ignores and
+    /// overrides of any matching entry win over both.
+    Priority,
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resolution::FirstMatch => write!(f, "first_match"),
+            Resolution::LastMatch => write!(f, "last_match"),
+            Resolution::Priority => write!(f, "priority"),
+        }
+    }
+}
+
+/// A window event and the layer which the configuration is expected to select
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TestCase {
+    /// Identifies the test case in the output of `komokana test`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub exe: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub class: String,
+    pub event: Event,
+    /// Virtual key codes which are held down when the event is handled
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub held_virtual_keys: Vec<i32>,
+    /// The layer which should be selected, or nothing if the layer shouldn't change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_layer: Option<String>,
+}
+
+impl Display for TestCase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{:?} {} {:?}", self.event, self.exe, self.title),
+        }
+    }
+}
+
+/// Brings `value` into NFC form, and additionally applies Unicode case folding
+/// when the comparison should not be case sensitive
+fn normalize(value: &str, case_sensitive: bool) -> String {
+    if case_sensitive {
+        value.nfc().collect()
+    } else {
+        value.chars().nfd().default_case_fold().nfc().collect()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct Entry {
+    /// Exe name of the windows which this entry applies to
     pub exe: String,
+    /// Layer to switch to when this entry matches
     pub target_layer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title_overrides: Option<Vec<TitleOverride>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_overrides: Option<Vec<ClassOverride>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub virtual_key_overrides: Option<Vec<VirtualKeyOverride>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub virtual_key_ignores: Option<Vec<i32>>,
+    /// An additional condition which must hold for this entry to match
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    #[schemars(with = "Option<Condition>")]
+    pub when: Option<Condition>,
+    /// Used with the priority resolution mode (default: 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Match the exe case sensitively (default: false), title and class
+    /// overrides set their own value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    /// Overrides the settle delay when this entry decides the layer, e.g. 0
+    /// for an instant switch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settle_delay_ms: Option<u64>,
+}
+
+impl Entry {
+    #[must_use]
+    pub fn matches_exe(&self, exe: &str) -> bool {
+        let case_sensitive = self.case_sensitive.unwrap_or(false);
+        normalize(&self.exe, case_sensitive) == normalize(exe, case_sensitive)
+    }
+
+    fn compile(&mut self) -> Result<()> {
+        if let Some(when) = &mut self.when {
+            when.compile()
+                .map_err(|error| anyhow!("when condition has an invalid regex: {error}"))?;
+        }
+
+        if let Some(title_overrides) = &mut self.title_overrides {
+            for (i, title_override) in title_overrides.iter_mut().enumerate() {
+                title_override
+                    .compile()
+                    .map_err(|error| anyhow!("title override {i} has an invalid regex: {error}"))?;
+            }
+        }
+
+        if let Some(class_overrides) = &mut self.class_overrides {
+            for (i, class_override) in class_overrides.iter_mut().enumerate() {
+                class_override
+                    .compile()
+                    .map_err(|error| anyhow!("class override {i} has an invalid regex: {error}"))?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct TitleOverride {
     pub title: String,
     pub strategy: Strategy,
     pub target_layer: String,
+    /// Match the title case sensitively (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    /// An additional condition which must hold for this override to match
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    #[schemars(with = "Option<Condition>")]
+    pub when: Option<Condition>,
+    /// Used with the priority resolution mode (default: the entry's value)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(skip)]
+    pub(crate) regex: Option<Pattern>,
+}
+
+impl TitleOverride {
+    #[must_use]
+    pub fn matches(&self, title: &str) -> bool {
+        let case_sensitive = self.case_sensitive.unwrap_or(true);
+
+        self.strategy
+            .is_match(&self.title, self.regex.as_ref(), title, case_sensitive)
+    }
+
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        if matches!(self.strategy, Strategy::Regex) {
+            let case_sensitive = self.case_sensitive.unwrap_or(true);
+            self.regex = Option::from(Pattern::new(&self.title, case_sensitive)?);
+        }
+
+        if let Some(when) = &mut self.when {
+            when.compile()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for TitleOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "title {} {:?}", self.strategy, self.title)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ClassOverride {
+    pub class: String,
+    pub strategy: Strategy,
+    pub target_layer: String,
+    /// Match the class case sensitively (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    /// An additional condition which must hold for this override to match
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    #[schemars(with = "Option<Condition>")]
+    pub when: Option<Condition>,
+    /// Used with the priority resolution mode (default: the entry's value)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(skip)]
+    pub(crate) regex: Option<Pattern>,
+}
+
+impl ClassOverride {
+    #[must_use]
+    pub fn matches(&self, class: &str) -> bool {
+        let case_sensitive = self.case_sensitive.unwrap_or(false);
+
+        self.strategy
+            .is_match(&self.class, self.regex.as_ref(), class, case_sensitive)
+    }
+
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        if matches!(self.strategy, Strategy::Regex) {
+            let case_sensitive = self.case_sensitive.unwrap_or(false);
+            self.regex = Option::from(Pattern::new(&self.class, case_sensitive)?);
+        }
+
+        if let Some(when) = &mut self.when {
+            when.compile()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ClassOverride {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "class {} {:?}", self.strategy, self.class)
+    }
+}
+
+/// A tree of predicates combined with `all`, `any` and `not`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    Exe(Matcher),
+    Title(Matcher),
+    Class(Matcher),
+    /// Holds when this virtual key code is held down
+    KeyHeld(i32),
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+impl Condition {
+    /// Exe names and window classes are compared case insensitively by
+    /// default, window titles are compared case sensitively by default
+    const fn default_case_sensitive(&self) -> bool {
+        matches!(self, Condition::Title(_))
+    }
+
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        let default_case_sensitive = self.default_case_sensitive();
+
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                for condition in conditions {
+                    condition.compile()?;
+                }
+            }
+            Condition::Not(condition) => condition.compile()?,
+            Condition::Exe(matcher) | Condition::Title(matcher) | Condition::Class(matcher) => {
+                matcher.compile(default_case_sensitive)?;
+            }
+            Condition::KeyHeld(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates the condition tree against a window, returning a description
+    /// of the branch which matched, or `None` if the condition doesn't hold
+    pub fn evaluate(&self, window: &WindowInfo, key_state: &dyn KeyState) -> Option<String> {
+        let default_case_sensitive = self.default_case_sensitive();
+
+        let matched = match self {
+            Condition::All(conditions) => {
+                let mut branches = vec![];
+                for condition in conditions {
+                    branches.push(condition.evaluate(window, key_state)?);
+                }
+
+                return Option::from(format!("all({})", branches.join(", ")));
+            }
+            Condition::Any(conditions) => {
+                return conditions.iter().enumerate().find_map(|(i, condition)| {
+                    condition
+                        .evaluate(window, key_state)
+                        .map(|branch| format!("any[{i}]({branch})"))
+                });
+            }
+            Condition::Not(condition) => condition.evaluate(window, key_state).is_none(),
+            Condition::Exe(matcher) => matcher.matches(&window.exe, default_case_sensitive),
+            Condition::Title(matcher) => matcher.matches(&window.title, default_case_sensitive),
+            Condition::Class(matcher) => matcher.matches(&window.class, default_case_sensitive),
+            Condition::KeyHeld(virtual_key_code) => key_state.is_held(*virtual_key_code),
+        };
+
+        matched.then(|| self.to_string())
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let name = if matches!(self, Condition::All(_)) {
+                    "all"
+                } else {
+                    "any"
+                };
+
+                write!(f, "{name}(")?;
+                for (i, condition) in conditions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{condition}")?;
+                }
+                write!(f, ")")
+            }
+            Condition::Not(condition) => write!(f, "not({condition})"),
+            Condition::Exe(matcher) => write!(f, "exe {matcher}"),
+            Condition::Title(matcher) => write!(f, "title {matcher}"),
+            Condition::Class(matcher) => write!(f, "class {matcher}"),
+            Condition::KeyHeld(virtual_key_code) => write!(f, "key_held {virtual_key_code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Matcher {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub strategy: Strategy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    #[serde(skip)]
+    pub(crate) regex: Option<Pattern>,
+}
+
+impl Matcher {
+    fn matches(&self, value: &str, default_case_sensitive: bool) -> bool {
+        let case_sensitive = self.case_sensitive.unwrap_or(default_case_sensitive);
+
+        self.strategy
+            .is_match(&self.value, self.regex.as_ref(), value, case_sensitive)
+    }
+
+    fn compile(&mut self, default_case_sensitive: bool) -> Result<(), regex::Error> {
+        if matches!(self.strategy, Strategy::Regex) {
+            let case_sensitive = self.case_sensitive.unwrap_or(default_case_sensitive);
+            self.regex = Option::from(Pattern::new(&self.value, case_sensitive)?);
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Matcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:?}", self.strategy, self.value)
+    }
+}
+
+/// An override regex, compiled once when the configuration is loaded
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    fn new(pattern: &str, case_sensitive: bool) -> Result<Self, regex::Error> {
+        RegexBuilder::new(pattern)
+            .case_insensitive(!case_sensitive)
+            .build()
+            .map(Self)
+    }
+
+    #[must_use]
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.0.is_match(haystack)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct VirtualKeyOverride {
     pub virtual_key_code: i32,
-    pub targer_layer: String,
+    /// Older configurations spell this `targer_layer`, which is still accepted
+    #[serde(alias = "targer_layer")]
+    pub target_layer: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Strategy {
     StartsWith,
     EndsWith,
     Contains,
+    #[default]
     Equals,
+    Regex,
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::StartsWith => write!(f, "starts_with"),
+            Strategy::EndsWith => write!(f, "ends_with"),
+            Strategy::Contains => write!(f, "contains"),
+            Strategy::Equals => write!(f, "equals"),
+            Strategy::Regex => write!(f, "regex"),
+        }
+    }
+}
+
+impl Strategy {
+    fn is_match(
+        &self,
+        pattern: &str,
+        regex: Option<&Pattern>,
+        value: &str,
+        case_sensitive: bool,
+    ) -> bool {
+        // case sensitivity is already baked into compiled regexes
+        let case_sensitive = case_sensitive || matches!(self, Strategy::Regex);
+        let pattern = normalize(pattern, case_sensitive);
+        let value = normalize(value, case_sensitive);
+
+        match self {
+            Strategy::StartsWith => value.starts_with(&pattern),
+            Strategy::EndsWith => value.ends_with(&pattern),
+            Strategy::Contains => value.contains(&pattern),
+            Strategy::Equals => value.eq(&pattern),
+            Strategy::Regex => regex.is_some_and(|regex| regex.is_match(&value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the files into a directory of their own and loads the first one
+    fn load_files(name: &str, files: &[(&str, &str)]) -> Result<Configuration> {
+        let directory =
+            std::env::temp_dir().join(format!("komokana-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+
+        for (file, contents) in files {
+            std::fs::write(directory.join(file), contents)?;
+        }
+
+        let path = resolve_windows_path(&directory.join(files[0].0).to_string_lossy())?;
+        let loaded = load(&path, Format::from_path(&path));
+        std::fs::remove_dir_all(&directory)?;
+
+        loaded
+    }
+
+    fn schema_accepts(instance: &serde_json::Value) -> bool {
+        let schema = serde_json::to_value(schema()).unwrap();
+        jsonschema::validator_for(&schema)
+            .unwrap()
+            .is_valid(instance)
+    }
+
+    #[test]
+    fn the_schema_accepts_the_example_configuration() {
+        let example: serde_json::Value =
+            serde_yaml::from_str(include_str!("../komokana.example.yaml")).unwrap();
+
+        assert!(schema_accepts(&example));
+        assert!(schema_accepts(&serde_json::json!({
+            "version": 1,
+            "resolution": "priority",
+            "rules": [{ "exe": "a.exe", "target_layer": "a", "priority": 1 }],
+        })));
+
+        assert!(!schema_accepts(&serde_json::json!({
+            "rules": [{ "exe": "a.exe" }],
+        })));
+        assert!(!schema_accepts(&serde_json::json!([{
+            "exe": "a.exe",
+            "target_layer": "a",
+            "title_overrides": [{ "title": "a", "strategy": "fuzzy", "target_layer": "b" }],
+        }])));
+    }
+
+    #[test]
+    fn configurations_convert_between_formats() {
+        let example = parse(include_str!("../komokana.example.yaml"), Format::Yaml).unwrap();
+
+        for format in [Format::Yaml, Format::Toml, Format::Json] {
+            let converted = format.serialize(&example).unwrap();
+            assert_eq!(parse(&converted, format).unwrap(), example, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn included_files_are_merged_with_the_including_file_winning() {
+        let loaded = load_files(
+            "merge",
+            &[
+                (
+                    "local.yaml",
+                    "include:\n  - team.toml\noutputs:\n  tmpfile: false\nrules:\n  - exe: b.exe\n    target_layer: local\n",
+                ),
+                (
+                    "team.toml",
+                    "resolution = \"priority\"\ndefault_layer = \"qwerty\"\n\n[outputs]\ntmpfile = true\n\n[[rules]]\nexe = \"a.exe\"\ntarget_layer = \"a\"\n\n[[rules]]\nexe = \"B.exe\"\ntarget_layer = \"b\"\n",
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.resolution, Some(Resolution::Priority));
+        assert_eq!(loaded.default_layer.as_deref(), Some("qwerty"));
+        assert_eq!(loaded.outputs.tmpfile, Some(false));
+        assert_eq!(
+            loaded
+                .rules
+                .iter()
+                .map(|entry| (entry.exe.as_str(), entry.target_layer.as_str()))
+                .collect::<Vec<_>>(),
+            [("a.exe", "a"), ("b.exe", "local")]
+        );
+        assert!(loaded.files[0].ends_with("local.yaml"));
+        assert!(loaded.files[1].ends_with("team.toml"));
+    }
+
+    #[test]
+    fn overlays_do_not_need_rules() {
+        let loaded = load_files(
+            "overlay",
+            &[
+                (
+                    "local.yaml",
+                    "include:\n  - team.yaml\nsettle_delay_ms: 100\n",
+                ),
+                ("team.yaml", "- exe: a.exe\n  target_layer: a\n"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(loaded.settle_delay_ms, Some(100));
+        assert_eq!(loaded.rules.len(), 1);
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let error = load_files(
+            "cycle",
+            &[
+                ("a.yaml", "include:\n  - b.yaml\n"),
+                ("b.yaml", "include:\n  - a.yaml\n"),
+            ],
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("include each other"), "{error}");
+    }
+
+    #[test]
+    fn default_settings_are_not_serialized() {
+        let configuration = parse("- exe: a.exe\n  target_layer: a\n", Format::Yaml).unwrap();
+
+        for format in [Format::Yaml, Format::Toml, Format::Json] {
+            let serialized = format.serialize(&configuration).unwrap();
+
+            for key in ["resolution", "tests", "kanata", "outputs", "reconnect"] {
+                assert!(!serialized.contains(key), "{format:?}: {serialized}");
+            }
+
+            assert_eq!(parse(&serialized, format).unwrap(), configuration);
+        }
+    }
 }
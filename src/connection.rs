@@ -0,0 +1,754 @@
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use color_eyre::eyre::anyhow;
+use color_eyre::Report;
+use color_eyre::Result;
+use parking_lot::RwLock;
+
+use crate::kanata::ClientMessage;
+use crate::kanata::KanataClient;
+use crate::kanata::ServerMessage;
+use crate::kanata::ServerMessages;
+use crate::kanata::ServerOutput;
+use crate::kanata::ServerResponse;
+use crate::reconnect::ReconnectPolicy;
+
+/// How long the desired layer is held back for kanata to say which layer it
+/// is on, since older versions of kanata don't answer at all
+const CURRENT_LAYER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The state of the connection to kanata, as last seen by its owner
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionState {
+    pub connected: bool,
+    /// The layers which kanata reported that it has, once it has responded
+    /// on the current connection
+    pub layer_names: Option<Vec<String>>,
+    /// The layer which kanata last reported that it is on
+    pub current_layer: Option<String>,
+    /// How many layer changes were sent to kanata
+    pub layer_changes_sent: u64,
+    /// How many layer changes weren't sent, because kanata was already on the layer
+    pub layer_changes_suppressed: u64,
+}
+
+/// What the owner of the connection passes on to the rest of komokana
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent<'a> {
+    /// The connection was lost and has been replaced
+    Reconnected,
+    /// kanata sent a message
+    Output(&'a ServerOutput),
+    /// kanata changed to a layer which komokana didn't ask for, e.g. because
+    /// of a `layer-switch` action
+    ExternalLayerChange(&'a str),
+    /// kanata changed back to the layer which komokana last asked for, e.g.
+    /// because a `layer-while-held` key was released
+    LayerRestored(&'a str),
+}
+
+/// Everything the owner of the connection reacts to, in the order it happened
+#[derive(Debug)]
+enum Input {
+    Command(ClientMessage),
+    Sync(String),
+    /// Without a layer, cancels the layer change which is waiting to settle
+    Settle {
+        layer: Option<String>,
+        delay: Duration,
+    },
+    Output {
+        generation: u64,
+        output: ServerOutput,
+    },
+    Closed {
+        generation: u64,
+        error: Option<std::io::Error>,
+    },
+}
+
+/// A handle to the thread which owns the connection to kanata.
+///
+/// Messages are queued to the owning thread rather than written directly, and
+/// the owning thread replaces the reading and writing sides of the connection
+/// together whenever it has to reconnect.
+///
+/// Layer changes are coalesced, so that only the latest layer is sent when
+/// several changes queue up, e.g. while kanata is unreachable, and aren't sent
+/// at all when kanata is already on the layer. Whenever the connection is
+/// replaced, kanata is asked for its current layer, and is switched to the
+/// latest layer if it isn't on it already.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    inputs: mpsc::Sender<Input>,
+    state: Arc<RwLock<ConnectionState>>,
+}
+
+impl Connection {
+    /// Connects to kanata and hands the connection over to a new thread,
+    /// which passes everything that happens on the connection to `on_event`,
+    /// and calls `on_give_up` if it has to stop trying to reconnect
+    pub fn spawn<F, G>(
+        port: i32,
+        policy: ReconnectPolicy,
+        on_event: F,
+        on_give_up: G,
+    ) -> Result<Self>
+    where
+        F: FnMut(&Self, ConnectionEvent<'_>) + Send + 'static,
+        G: FnOnce(Report) + Send + 'static,
+    {
+        let (inputs, receiver) = mpsc::channel();
+        let handle = Self {
+            inputs,
+            state: Arc::new(RwLock::new(ConnectionState::default())),
+        };
+
+        let mut owner = Owner {
+            port,
+            policy,
+            client: KanataClient::connect(port)?,
+            generation: 0,
+            handle: handle.clone(),
+            on_event: Box::new(on_event),
+            desired_layer: None,
+            layer_pending: false,
+            awaiting_current_layer: None,
+            in_flight: VecDeque::new(),
+            settling: None,
+        };
+
+        owner.connected()?;
+        std::thread::spawn(move || {
+            if let Err(error) = owner.run(&receiver) {
+                on_give_up(error);
+            }
+        });
+
+        Ok(handle)
+    }
+
+    /// Asks kanata to switch to the given layer
+    pub fn change_layer(&self, layer: &str) -> Result<()> {
+        self.send(ClientMessage::ChangeLayer {
+            new: layer.to_string(),
+        })
+    }
+
+    /// Asks kanata which layer it is on, and only switches kanata to the
+    /// given layer if it is on a different one
+    pub fn sync_layer(&self, layer: &str) -> Result<()> {
+        self.queue(Input::Sync(layer.to_string()))
+    }
+
+    /// Asks kanata to switch to the given layer once nothing else has been
+    /// asked for within the delay, so that only the last of several changes
+    /// in quick succession is sent. Without a layer, the change which is
+    /// waiting to settle is cancelled, e.g. when focus moves to a window which
+    /// shouldn't change the layer.
+    pub fn settle_layer(&self, layer: Option<&str>, delay: Duration) -> Result<()> {
+        self.queue(Input::Settle {
+            layer: layer.map(String::from),
+            delay,
+        })
+    }
+
+    /// Queues a message to be sent to kanata
+    pub fn send(&self, message: ClientMessage) -> Result<()> {
+        self.queue(Input::Command(message))
+    }
+
+    fn queue(&self, input: Input) -> Result<()> {
+        self.inputs
+            .send(input)
+            .map_err(|_| anyhow!("the connection to kanata has shut down"))
+    }
+
+    #[must_use]
+    pub fn state(&self) -> ConnectionState {
+        self.state.read().clone()
+    }
+}
+
+type EventHandler = Box<dyn FnMut(&Connection, ConnectionEvent<'_>) + Send>;
+
+/// The only owner of the socket connected to kanata
+struct Owner {
+    port: i32,
+    policy: ReconnectPolicy,
+    client: KanataClient,
+    /// Incremented for every connection, so that anything read from a
+    /// connection which has since been replaced can be ignored
+    generation: u64,
+    handle: Connection,
+    on_event: EventHandler,
+    /// The latest layer which kanata was asked to change to
+    desired_layer: Option<String>,
+    /// Whether the desired layer still has to be sent on the current connection
+    layer_pending: bool,
+    /// When the desired layer is waiting on kanata to say which layer it is
+    /// on, the time after which it is sent regardless
+    awaiting_current_layer: Option<Instant>,
+    /// Layer changes which were sent, but which kanata hasn't reported yet
+    in_flight: VecDeque<String>,
+    /// A layer change which is waiting for its settle delay to pass
+    settling: Option<(String, Instant)>,
+}
+
+impl Owner {
+    fn run(mut self, receiver: &mpsc::Receiver<Input>) -> Result<()> {
+        loop {
+            let deadline = [
+                self.settling.as_ref().map(|(_, deadline)| *deadline),
+                self.awaiting_current_layer,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            let input = match deadline {
+                Some(deadline) => {
+                    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(input) => Some(input),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(input) => Some(input),
+                    Err(mpsc::RecvError) => break,
+                },
+            };
+
+            if let Some(input) = input {
+                self.handle(input)?;
+
+                // Handle everything which queued up in the meantime before
+                // sending a layer change, so that only the latest layer is sent
+                while let Ok(input) = receiver.try_recv() {
+                    self.handle(input)?;
+                }
+            } else {
+                self.deadline_passed();
+            }
+
+            self.send_desired_layer()?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, input: Input) -> Result<()> {
+        match input {
+            Input::Command(ClientMessage::ChangeLayer { new }) => {
+                self.settling = None;
+                self.desired_layer = Some(new);
+                self.layer_pending = true;
+                self.awaiting_current_layer = None;
+            }
+            Input::Command(message) => {
+                if let Err(error) = self.client.send(&message) {
+                    log::warn!("failed to send {message:?} to kanata: {error}");
+                    self.reconnect()?;
+                }
+            }
+            Input::Settle { layer, delay } => {
+                self.settling = layer.map(|layer| (layer, Instant::now() + delay));
+            }
+            Input::Sync(layer) => {
+                self.settling = None;
+                self.desired_layer = Some(layer);
+                self.layer_pending = false;
+                if let Err(error) = self.request_current_layer() {
+                    log::warn!("failed to ask kanata for its current layer: {error}");
+                    self.reconnect()?;
+                }
+            }
+            Input::Output { generation, output } if generation == self.generation => {
+                let mut external = None;
+                let mut restored = None;
+
+                match &output {
+                    ServerOutput::Message(ServerMessage::LayerNames { names }) => {
+                        self.handle.state.write().layer_names = Some(names.clone());
+                    }
+                    ServerOutput::Message(ServerMessage::LayerChange { new }) => {
+                        // Every change up to this one has been handled by kanata
+                        if let Some(position) = self.in_flight.iter().position(|sent| sent == new) {
+                            self.in_flight.drain(..=position);
+                        } else {
+                            if self.desired_layer.as_ref() == Some(new) {
+                                restored = Some(new.clone());
+                            } else if self.expected_layer().as_ref() != Some(new) {
+                                // A change made in kanata is more recent than
+                                // any change which is still waiting to settle
+                                self.settling = None;
+                                external = Some(new.clone());
+                            }
+
+                            self.in_flight.clear();
+                        }
+
+                        self.handle.state.write().current_layer = Some(new.clone());
+                    }
+                    ServerOutput::Message(ServerMessage::CurrentLayerName { name }) => {
+                        self.handle.state.write().current_layer = Some(name.clone());
+                        if self.awaiting_current_layer.take().is_some() {
+                            self.layer_pending = true;
+                        }
+                    }
+                    // Versions of kanata which can't report their current
+                    // layer are sent the desired layer regardless
+                    ServerOutput::Response(ServerResponse::Error { .. })
+                        if self.awaiting_current_layer.is_some() =>
+                    {
+                        self.awaiting_current_layer = None;
+                        self.layer_pending = true;
+                    }
+                    _ => {}
+                }
+
+                (self.on_event)(&self.handle, ConnectionEvent::Output(&output));
+
+                if let Some(layer) = &external {
+                    (self.on_event)(&self.handle, ConnectionEvent::ExternalLayerChange(layer));
+                }
+
+                if let Some(layer) = &restored {
+                    (self.on_event)(&self.handle, ConnectionEvent::LayerRestored(layer));
+                }
+            }
+            Input::Closed { generation, error } if generation == self.generation => {
+                match error {
+                    Some(error) => log::warn!("lost the connection to kanata: {error}"),
+                    None => log::warn!("kanata tcp server is no longer running"),
+                }
+
+                self.reconnect()?;
+            }
+            // Left over from a connection which has already been replaced
+            Input::Output { .. } | Input::Closed { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// Sends a layer which has settled, or the desired layer if kanata never
+    /// said which layer it is on
+    fn deadline_passed(&mut self) {
+        let now = Instant::now();
+
+        if self
+            .awaiting_current_layer
+            .is_some_and(|deadline| deadline <= now)
+        {
+            log::warn!("kanata didn't say which layer it is on, sending the layer regardless");
+            self.awaiting_current_layer = None;
+            self.layer_pending = true;
+        }
+
+        if let Some((layer, _)) = self.settling.take_if(|(_, deadline)| *deadline <= now) {
+            self.desired_layer = Some(layer);
+            self.layer_pending = true;
+            self.awaiting_current_layer = None;
+        }
+    }
+
+    /// The layer which kanata will be on once it has handled every layer
+    /// change sent so far
+    fn expected_layer(&self) -> Option<String> {
+        self.in_flight
+            .back()
+            .cloned()
+            .or_else(|| self.handle.state.read().current_layer.clone())
+    }
+
+    fn send_desired_layer(&mut self) -> Result<()> {
+        while self.layer_pending {
+            let Some(layer) = self.desired_layer.clone() else {
+                break;
+            };
+
+            if self.expected_layer().as_ref() == Some(&layer) {
+                self.layer_pending = false;
+
+                let (sent, suppressed) = {
+                    let mut state = self.handle.state.write();
+                    state.layer_changes_suppressed += 1;
+                    (state.layer_changes_sent, state.layer_changes_suppressed)
+                };
+
+                log::debug!(
+                    "kanata is already on layer {layer}, not sending it again ({sent} sent, {suppressed} suppressed)"
+                );
+
+                break;
+            }
+
+            match self.client.change_layer(&layer) {
+                Ok(()) => {
+                    self.layer_pending = false;
+                    self.in_flight.push_back(layer);
+                    self.handle.state.write().layer_changes_sent += 1;
+                }
+                Err(error) => {
+                    log::warn!("failed to change kanata to layer {layer}: {error}");
+                    self.reconnect()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Holds back the desired layer until kanata has said which layer it is on
+    fn request_current_layer(&mut self) -> Result<()> {
+        self.awaiting_current_layer = Some(Instant::now() + CURRENT_LAYER_TIMEOUT);
+        self.client.send(&ClientMessage::RequestCurrentLayerName {})
+    }
+
+    /// Starts reading from the current connection, and asks kanata for the
+    /// names of its layers, since it may have restarted with a different
+    /// configuration
+    fn connected(&mut self) -> Result<()> {
+        self.generation += 1;
+        let generation = self.generation;
+        let inputs = self.handle.inputs.clone();
+        let mut messages = ServerMessages::new(BufReader::new(self.client.try_clone()?));
+
+        std::thread::spawn(move || loop {
+            let input = match messages.next_message() {
+                Ok(Some(output)) => Input::Output { generation, output },
+                Ok(None) => Input::Closed {
+                    generation,
+                    error: None,
+                },
+                Err(error) if error.kind() == ErrorKind::InvalidData => {
+                    log::error!("kanata sent a malformed message: {error}");
+                    continue;
+                }
+                Err(error) => Input::Closed {
+                    generation,
+                    error: Some(error),
+                },
+            };
+
+            let closed = matches!(input, Input::Closed { .. });
+            if inputs.send(input).is_err() || closed {
+                break;
+            }
+        });
+
+        // The response is sent back on the connection that asked for it
+        self.client.request_layer_names()?;
+
+        // A new connection may well be to a kanata which has just started
+        self.layer_pending = false;
+        self.awaiting_current_layer = None;
+        if self.desired_layer.is_some() {
+            self.request_current_layer()?;
+        }
+
+        self.handle.state.write().connected = true;
+
+        Ok(())
+    }
+
+    /// Replaces the connection, reading and writing, with a new one
+    fn reconnect(&mut self) -> Result<()> {
+        // kanata may come back with a different configuration, on any layer
+        self.in_flight.clear();
+        {
+            let mut state = self.handle.state.write();
+            state.connected = false;
+            state.layer_names = None;
+            state.current_layer = None;
+        }
+
+        // Unblocks the read thread of the connection being replaced
+        if let Err(error) = self.client.shutdown() {
+            log::debug!("failed to shut down the previous kanata connection: {error}");
+        }
+
+        let policy = self.policy;
+        policy.retry("kanata", || {
+            self.client = KanataClient::connect(self.port)?;
+            self.connected().inspect_err(|_| {
+                // Don't leave a read thread behind for a connection which won't be used
+                let _ = self.client.shutdown();
+            })
+        })?;
+
+        (self.on_event)(&self.handle, ConnectionEvent::Reconnected);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Write;
+    use std::net::Shutdown;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stands in for kanata's TCP server, answering requests for its layer
+    /// names and current layer, and passing on every message it receives
+    struct FakeKanata {
+        port: u16,
+        /// Only listening while kanata is running
+        listener: Option<TcpListener>,
+        current_layer: Arc<RwLock<String>>,
+        sender: mpsc::Sender<ClientMessage>,
+        received: mpsc::Receiver<ClientMessage>,
+    }
+
+    /// kanata's side of a connection
+    struct Session {
+        stream: TcpStream,
+        current_layer: Arc<RwLock<String>>,
+    }
+
+    impl FakeKanata {
+        fn new(current_layer: &str) -> Self {
+            let (sender, received) = mpsc::channel();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+            Self {
+                port: listener.local_addr().unwrap().port(),
+                listener: Some(listener),
+                current_layer: Arc::new(RwLock::new(current_layer.to_string())),
+                sender,
+                received,
+            }
+        }
+
+        fn port(&self) -> i32 {
+            i32::from(self.port)
+        }
+
+        /// Stops kanata, so that connecting to it is refused until it is started again
+        fn stop(&mut self, session: &Session) {
+            session.stream.shutdown(Shutdown::Both).unwrap();
+            self.listener = None;
+        }
+
+        fn start(&mut self) {
+            self.listener = Some(TcpListener::bind(("127.0.0.1", self.port)).unwrap());
+        }
+
+        fn accept(&self) -> Session {
+            let (stream, _) = self.listener.as_ref().unwrap().accept().unwrap();
+            let reader = stream.try_clone().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let current_layer = self.current_layer.clone();
+            let sender = self.sender.clone();
+
+            std::thread::spawn(move || {
+                let messages =
+                    serde_json::Deserializer::from_reader(reader).into_iter::<ClientMessage>();
+
+                for message in messages {
+                    let Ok(message) = message else {
+                        break;
+                    };
+
+                    let reply = match message {
+                        ClientMessage::RequestLayerNames {} => {
+                            Some(json!({ "LayerNames": { "names": ["base", "a", "b"] } }))
+                        }
+                        ClientMessage::RequestCurrentLayerName {} => {
+                            Some(json!({ "CurrentLayerName": { "name": *current_layer.read() } }))
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(reply) = reply {
+                        let _ = writeln!(writer, "{reply}");
+                    }
+
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Session {
+                stream,
+                current_layer: self.current_layer.clone(),
+            }
+        }
+
+        fn next(&self) -> ClientMessage {
+            self.received.recv_timeout(TIMEOUT).unwrap()
+        }
+
+        fn assert_idle(&self) {
+            let received = self.received.recv_timeout(Duration::from_millis(200));
+            assert!(received.is_err(), "{received:?}");
+        }
+    }
+
+    impl Session {
+        /// Switches kanata to a layer, as if a key bound to `layer-switch` was pressed
+        fn layer_change(&mut self, layer: &str) {
+            layer.clone_into(&mut self.current_layer.write());
+            writeln!(
+                self.stream,
+                "{}",
+                json!({ "LayerChange": { "new": layer } })
+            )
+            .unwrap();
+        }
+    }
+
+    fn change_layer(layer: &str) -> ClientMessage {
+        ClientMessage::ChangeLayer {
+            new: layer.to_string(),
+        }
+    }
+
+    /// Connects to kanata, passing on the events which aren't just kanata's output
+    fn connect(kanata: &FakeKanata) -> (Connection, Session, mpsc::Receiver<String>) {
+        let (sender, events) = mpsc::channel();
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            max_attempts: None,
+        };
+
+        let connection = Connection::spawn(
+            kanata.port(),
+            policy,
+            move |_, event| {
+                let event = match event {
+                    ConnectionEvent::Output(_) => return,
+                    ConnectionEvent::Reconnected => String::from("reconnected"),
+                    ConnectionEvent::ExternalLayerChange(layer) => format!("external {layer}"),
+                    ConnectionEvent::LayerRestored(layer) => format!("restored {layer}"),
+                };
+
+                let _ = sender.send(event);
+            },
+            |_| {},
+        )
+        .unwrap();
+
+        let session = kanata.accept();
+        assert_eq!(kanata.next(), ClientMessage::RequestLayerNames {});
+
+        (connection, session, events)
+    }
+
+    #[test]
+    fn layer_changes_are_only_sent_when_kanata_is_on_another_layer() {
+        let kanata = FakeKanata::new("base");
+        let (connection, mut session, events) = connect(&kanata);
+
+        // kanata is already on the layer
+        connection.sync_layer("base").unwrap();
+        assert_eq!(kanata.next(), ClientMessage::RequestCurrentLayerName {});
+        kanata.assert_idle();
+
+        connection.change_layer("a").unwrap();
+        assert_eq!(kanata.next(), change_layer("a"));
+        connection.change_layer("b").unwrap();
+        assert_eq!(kanata.next(), change_layer("b"));
+
+        // kanata hasn't reported either change yet, but will end up on b
+        connection.change_layer("b").unwrap();
+        kanata.assert_idle();
+
+        let state = connection.state();
+        assert_eq!(state.layer_changes_sent, 2);
+        assert_eq!(state.layer_changes_suppressed, 2);
+
+        // Reports of layers which were asked for aren't changes made in kanata
+        session.layer_change("a");
+        session.layer_change("b");
+        kanata.assert_idle();
+        assert_eq!(connection.state().current_layer.as_deref(), Some("b"));
+        assert!(events.try_recv().is_err());
+
+        // With every change reported, kanata changing layer is noticed
+        session.layer_change("a");
+        assert_eq!(events.recv_timeout(TIMEOUT).unwrap(), "external a");
+    }
+
+    #[test]
+    fn only_the_latest_layer_is_sent_after_reconnecting() {
+        let mut kanata = FakeKanata::new("base");
+        let (connection, mut session, events) = connect(&kanata);
+
+        connection.change_layer("a").unwrap();
+        assert_eq!(kanata.next(), change_layer("a"));
+        session.layer_change("a");
+
+        kanata.stop(&session);
+        while connection.state().connected {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        for layer in ["b", "base", "b"] {
+            connection.change_layer(layer).unwrap();
+        }
+
+        // Attempts to reconnect are refused for a while
+        std::thread::sleep(Duration::from_millis(100));
+        kanata.start();
+        let _session = kanata.accept();
+
+        assert_eq!(kanata.next(), ClientMessage::RequestLayerNames {});
+        assert_eq!(kanata.next(), ClientMessage::RequestCurrentLayerName {});
+        assert_eq!(kanata.next(), change_layer("b"));
+        kanata.assert_idle();
+        assert_eq!(events.recv_timeout(TIMEOUT).unwrap(), "reconnected");
+    }
+
+    #[test]
+    fn settling_layer_changes_can_be_cancelled() {
+        let kanata = FakeKanata::new("base");
+        let (connection, _session, _events) = connect(&kanata);
+
+        connection
+            .settle_layer(Some("a"), Duration::from_millis(100))
+            .unwrap();
+        connection.settle_layer(None, Duration::ZERO).unwrap();
+        kanata.assert_idle();
+
+        connection
+            .settle_layer(Some("b"), Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(kanata.next(), change_layer("b"));
+    }
+
+    #[test]
+    fn layer_changes_made_in_kanata_are_told_apart_from_restored_layers() {
+        let kanata = FakeKanata::new("base");
+        let (connection, mut session, events) = connect(&kanata);
+
+        connection.change_layer("a").unwrap();
+        assert_eq!(kanata.next(), change_layer("a"));
+        session.layer_change("a");
+
+        // e.g. a layer-while-held key is pressed, and then released
+        session.layer_change("b");
+        assert_eq!(events.recv_timeout(TIMEOUT).unwrap(), "external b");
+        session.layer_change("a");
+        assert_eq!(events.recv_timeout(TIMEOUT).unwrap(), "restored a");
+
+        kanata.assert_idle();
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,620 @@
+use komorebi_client::SocketMessage;
+use komorebi_client::Window;
+use komorebi_client::WindowsApi;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::anyhow;
+use color_eyre::Report;
+use color_eyre::Result;
+use komorebi_client::Notification;
+use komorebi_client::NotificationEvent;
+use komorebi_client::UnixListener;
+use komorebi_client::WindowManagerEvent;
+use notify::RecursiveMode;
+use notify::Watcher;
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+
+use crate::configuration;
+use crate::configuration::Format;
+use crate::configuration::ManualLock;
+use crate::configuration::Reconnect;
+use crate::connection::Connection;
+use crate::connection::ConnectionEvent;
+use crate::engine::Decision;
+use crate::engine::Event;
+use crate::engine::RuleEngine;
+use crate::engine::WindowInfo;
+use crate::kanata::ServerMessage;
+use crate::kanata::ServerOutput;
+use crate::kanata::ServerResponse;
+use crate::key_state::WindowsKeyState;
+use crate::lock::Lock;
+use crate::reconnect::ReconnectPolicy;
+use crate::validation;
+use crate::validation::Problem;
+
+const NAME: &str = "komokana.sock";
+
+pub struct Komokana {
+    komorebi: Arc<Mutex<UnixListener>>,
+    kanata: Connection,
+    configuration: PathBuf,
+    format: Format,
+    default_layer: Option<String>,
+    settle_delay_ms: Option<u64>,
+    engine: Arc<RwLock<Arc<RuleEngine>>>,
+    lock: Arc<Mutex<Option<Lock>>>,
+    reconnect: ReconnectPolicy,
+    explain: bool,
+    /// Receives the reason komokana stopped, when it gives up reconnecting
+    stopped: mpsc::Receiver<Report>,
+    stop: mpsc::Sender<Report>,
+}
+
+impl Komokana {
+    /// Settings passed as arguments take precedence over those in the configuration file
+    #[allow(clippy::too_many_arguments)]
+    pub fn init(
+        configuration: &Path,
+        format: Format,
+        kanata_port: Option<i32>,
+        default_layer: Option<String>,
+        settle_delay_ms: Option<u64>,
+        tmpfile: bool,
+        reconnect: Reconnect,
+        manual_lock: Option<ManualLock>,
+        explain: bool,
+    ) -> Result<Self> {
+        let mut loaded = configuration::load(configuration, format)?;
+
+        let kanata_port = kanata_port.or(loaded.kanata.port).ok_or_else(|| {
+            anyhow!("a kanata port is required, either as an argument or as kanata.port in the configuration file")
+        })?;
+
+        if default_layer.is_none() && loaded.default_layer.is_none() {
+            return Err(anyhow!(
+                "a default layer is required, either as an argument or as default_layer in the configuration file"
+            ));
+        }
+
+        loaded.settle_delay_ms = settle_delay_ms.or(loaded.settle_delay_ms);
+        let tmpfile = tmpfile || loaded.outputs.tmpfile.unwrap_or(false);
+        let reconnect = ReconnectPolicy::from(reconnect.or(loaded.reconnect));
+        let manual_lock = manual_lock.or(loaded.manual_lock);
+        let engine = Arc::new(RwLock::new(Arc::new(RuleEngine::new(
+            loaded,
+            default_layer.clone(),
+        )?)));
+
+        let listener = komorebi_client::subscribe(NAME)?;
+        log::debug!("connected to komorebi");
+
+        let (stop, stopped) = mpsc::channel();
+        let lock = Arc::new(Mutex::new(None));
+        let rules = engine.clone();
+        let paused = lock.clone();
+        let give_up = stop.clone();
+        let kanata = Connection::spawn(
+            kanata_port,
+            reconnect,
+            move |kanata, event| match event {
+                // kanata may have been restarted on a different layer, which
+                // also undoes any layer change made by hand
+                ConnectionEvent::Reconnected => {
+                    *paused.lock() = None;
+                    sync(&rules.read(), kanata, &paused, explain);
+                }
+                ConnectionEvent::Output(output) => {
+                    handle_kanata_output(output, &rules.read(), tmpfile);
+                }
+                ConnectionEvent::ExternalLayerChange(layer) => {
+                    if let Some(manual_lock) = manual_lock {
+                        pause(&paused, manual_lock, layer);
+                    }
+                }
+                ConnectionEvent::LayerRestored(layer) => {
+                    if paused.lock().take().is_some() {
+                        log::info!(
+                            "layer changed back to {layer} in kanata, resuming automatic switching"
+                        );
+                    }
+                }
+            },
+            move |error| {
+                let _ = give_up.send(error);
+            },
+        )?;
+        log::debug!("connected to kanata");
+
+        Ok(Self {
+            komorebi: Arc::new(Mutex::new(listener)),
+            kanata,
+            configuration: configuration.to_path_buf(),
+            format,
+            default_layer,
+            settle_delay_ms,
+            engine,
+            lock,
+            reconnect,
+            explain,
+            stopped,
+            stop,
+        })
+    }
+
+    /// Blocks until komokana gives up reconnecting to komorebi or kanata
+    pub fn wait(&self) -> Report {
+        self.stopped
+            .recv()
+            .unwrap_or_else(|_| anyhow!("komokana stopped unexpectedly"))
+    }
+
+    #[allow(clippy::too_many_lines)]
+    pub fn listen(&self) {
+        let socket = self.komorebi.clone();
+        let reconnect = self.reconnect;
+        let stop = self.stop.clone();
+        let explain = self.explain;
+        log::info!("listening");
+
+        // The focused window may not match the layer which kanata is on
+        sync(&self.engine.read(), &self.kanata, &self.lock, explain);
+
+        let configuration = self.configuration.clone();
+        let format = self.format;
+        let default_layer = self.default_layer.clone();
+        let settle_delay_ms = self.settle_delay_ms;
+        let engine = self.engine.clone();
+        let kanata = self.kanata.clone();
+        let lock = self.lock.clone();
+        std::thread::spawn(move || {
+            let watch = || -> Result<()> {
+                let (tx, rx) = mpsc::channel();
+                let mut watcher = notify::recommended_watcher(tx)?;
+
+                let mut directories = HashSet::new();
+                let mut files = engine.read().configuration().files.clone();
+                watch_directories(&mut watcher, &mut directories, &files)?;
+
+                while let Ok(result) = rx.recv() {
+                    match result {
+                        Ok(event)
+                            if (event.kind.is_create() || event.kind.is_modify())
+                                && event.paths.iter().any(|path| files.contains(path)) =>
+                        {
+                            // A single save can produce a burst of events, wait for it to settle
+                            std::thread::sleep(Duration::from_millis(100));
+                            while rx.try_recv().is_ok() {}
+
+                            reload(
+                                &configuration,
+                                format,
+                                default_layer.as_deref(),
+                                settle_delay_ms,
+                                &engine,
+                                &kanata,
+                                &lock,
+                                explain,
+                            );
+
+                            // The reloaded configuration may include different files
+                            files.clone_from(&engine.read().configuration().files);
+                            if let Err(error) =
+                                watch_directories(&mut watcher, &mut directories, &files)
+                            {
+                                log::error!("failed to watch included files: {error}");
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(error) => log::error!("failed to watch configuration: {error}"),
+                    }
+                }
+
+                Ok(())
+            };
+
+            if let Err(error) = watch() {
+                log::error!("failed to watch configuration, changes will not be reloaded: {error}");
+            }
+        });
+
+        let engine = self.engine.clone();
+        let kanata = self.kanata.clone();
+        let lock = self.lock.clone();
+        std::thread::spawn(move || -> Result<()> {
+            let mut subscribed = false;
+
+            #[allow(clippy::significant_drop_in_scrutinee)]
+            for client in socket.lock().incoming() {
+                let error = match client {
+                    Ok(subscription) => {
+                        // Focus may have moved while komorebi was unreachable,
+                        // e.g. while it was being restarted
+                        if subscribed {
+                            sync(&engine.read(), &kanata, &lock, explain);
+                        }
+
+                        subscribed = true;
+
+                        let reader = BufReader::new(subscription.try_clone()?);
+                        let mut error = None;
+
+                        for line in reader.lines() {
+                            let line = match line {
+                                Ok(line) => line,
+                                Err(read_error) => {
+                                    error = Some(read_error);
+                                    break;
+                                }
+                            };
+
+                            let notification: Notification = match serde_json::from_str(&line) {
+                                Ok(value) => value,
+                                Err(error) => {
+                                    log::debug!(
+                                        "discarding malformed komorebi notification: {error}"
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            let result = match notification.event {
+                                NotificationEvent::WindowManager(WindowManagerEvent::Show(
+                                    _,
+                                    window,
+                                )) => match WindowInfo::try_from(&window) {
+                                    Ok(info) => handle_event(
+                                        &engine.read().clone(),
+                                        &kanata,
+                                        &lock,
+                                        Event::Show,
+                                        window.hwnd,
+                                        &info,
+                                        explain,
+                                    ),
+                                    Err(error) => {
+                                        log::error!("failed to read window info: {error}");
+                                        continue;
+                                    }
+                                },
+                                NotificationEvent::WindowManager(
+                                    WindowManagerEvent::FocusChange(_, window),
+                                ) => match WindowInfo::try_from(&window) {
+                                    Ok(info) => handle_event(
+                                        &engine.read().clone(),
+                                        &kanata,
+                                        &lock,
+                                        Event::FocusChange,
+                                        window.hwnd,
+                                        &info,
+                                        explain,
+                                    ),
+                                    Err(error) => {
+                                        log::error!("failed to read window info: {error}");
+                                        continue;
+                                    }
+                                },
+                                NotificationEvent::Socket(
+                                    SocketMessage::CycleFocusWindow(_)
+                                    | SocketMessage::FocusStackWindow(_)
+                                    | SocketMessage::PromoteFocus
+                                    | SocketMessage::EagerFocus(_)
+                                    | SocketMessage::FocusWindow(_),
+                                ) => match foreground_window_info() {
+                                    Ok(Some((hwnd, info))) => handle_event(
+                                        &engine.read().clone(),
+                                        &kanata,
+                                        &lock,
+                                        Event::FocusChange,
+                                        hwnd,
+                                        &info,
+                                        explain,
+                                    ),
+                                    Ok(None) => continue,
+                                    Err(error) => {
+                                        log::error!("failed to read foreground window: {error}");
+                                        continue;
+                                    }
+                                },
+                                _ => Ok(()),
+                            };
+
+                            if let Err(error) = result {
+                                log::error!("handle_event failed: {error}");
+                            }
+                        }
+
+                        // Only a read error means that komorebi has to be resubscribed
+                        match error {
+                            Some(error) => error,
+                            None => continue,
+                        }
+                    }
+                    Err(error) => error,
+                };
+
+                log::warn!("lost the connection to komorebi: {error}");
+
+                let resubscribed = reconnect.retry("komorebi", || {
+                    let output = Command::new("cmd.exe")
+                        .args(["/C", "komorebic.exe", "subscribe-socket", NAME])
+                        .output()?;
+
+                    if output.status.success() {
+                        Ok(())
+                    } else {
+                        Err(anyhow!(
+                            "komorebic.exe failed with error code {:?}",
+                            output.status.code()
+                        ))
+                    }
+                });
+
+                if let Err(error) = resubscribed {
+                    let _ = stop.send(error);
+                    break;
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+/// Watches the directories containing the configuration files, since editors
+/// often save by replacing a file, which would drop a watch on the file itself
+fn watch_directories(
+    watcher: &mut impl Watcher,
+    directories: &mut HashSet<PathBuf>,
+    files: &[PathBuf],
+) -> Result<()> {
+    for file in files {
+        if let Some(directory) = file.parent() {
+            if directories.insert(directory.to_path_buf()) {
+                watcher.watch(directory, RecursiveMode::NonRecursive)?;
+                log::info!("watching {} for changes", directory.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reload(
+    configuration: &Path,
+    format: Format,
+    default_layer: Option<&str>,
+    settle_delay_ms: Option<u64>,
+    engine: &RwLock<Arc<RuleEngine>>,
+    kanata: &Connection,
+    lock: &Mutex<Option<Lock>>,
+    explain: bool,
+) {
+    let reloaded = match configuration::load(configuration, format).and_then(|mut loaded| {
+        loaded.settle_delay_ms = settle_delay_ms.or(loaded.settle_delay_ms);
+        RuleEngine::new(loaded, default_layer.map(String::from))
+    }) {
+        Ok(engine) => Arc::new(engine),
+        Err(error) => {
+            log::error!(
+                "failed to reload {}, keeping the previous rules: {error}",
+                configuration.display()
+            );
+            return;
+        }
+    };
+
+    *engine.write() = reloaded.clone();
+    log::info!("reloaded {}", configuration.display());
+
+    if let Some(known) = kanata.state().layer_names {
+        warn_unknown_layers(&reloaded, &known);
+    }
+
+    sync(&reloaded, kanata, lock, explain);
+}
+
+/// Switches kanata to the layer for the focused window, unless kanata is
+/// already on it
+fn sync(engine: &RuleEngine, kanata: &Connection, lock: &Mutex<Option<Lock>>, explain: bool) {
+    let (hwnd, info) = match foreground_window_info() {
+        Ok(Some(window)) => window,
+        Ok(None) => return,
+        Err(error) => {
+            log::error!("failed to read foreground window: {error}");
+            return;
+        }
+    };
+
+    if locked(lock, Event::FocusChange, hwnd) {
+        return;
+    }
+
+    if let Some(target) = decide(engine, kanata, Event::FocusChange, &info, explain).layer {
+        if let Err(error) = kanata.sync_layer(&target) {
+            log::error!("failed to sync kanata with the focused window: {error}");
+        }
+    }
+}
+
+fn foreground_window_info() -> Result<Option<(isize, WindowInfo)>> {
+    let hwnd = match WindowsApi::foreground_window() {
+        Ok(hwnd) => hwnd,
+        Err(error) => {
+            // os error 0 means GetForegroundWindow returned NULL
+            // with no error set — no window has focus right now
+            if error
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                == Some(0)
+            {
+                return Ok(None);
+            }
+
+            return Err(error);
+        }
+    };
+
+    Ok(Some((hwnd, WindowInfo::try_from(&Window::from(hwnd))?)))
+}
+
+/// Pauses automatic switching after kanata changed to a layer by hand
+fn pause(lock: &Mutex<Option<Lock>>, manual_lock: ManualLock, layer: &str) {
+    let hwnd = match foreground_window_info() {
+        Ok(window) => window.map(|(hwnd, _)| hwnd),
+        Err(error) => {
+            log::error!("failed to read foreground window: {error}");
+            None
+        }
+    };
+
+    match manual_lock.duration_ms {
+        Some(duration_ms) => log::info!(
+            "layer changed to {layer} by hand, pausing automatic switching for {duration_ms}ms"
+        ),
+        None => log::info!(
+            "layer changed to {layer} by hand, pausing automatic switching until focus moves to a different window"
+        ),
+    }
+
+    *lock.lock() = Some(Lock::new(manual_lock, hwnd));
+}
+
+/// Whether automatic switching is paused for an event, releasing the lock
+/// once it no longer holds
+fn locked(lock: &Mutex<Option<Lock>>, event: Event, hwnd: isize) -> bool {
+    let mut lock = lock.lock();
+
+    match *lock {
+        Some(held) if held.holds(event, hwnd) => {
+            log::debug!("automatic switching is paused, ignoring {event:?}");
+            true
+        }
+        Some(_) => {
+            *lock = None;
+            log::info!("resuming automatic switching");
+            false
+        }
+        None => false,
+    }
+}
+
+fn handle_kanata_output(output: &ServerOutput, engine: &RuleEngine, tmpfile: bool) {
+    match output {
+        ServerOutput::Message(ServerMessage::LayerChange { new }) => {
+            log::info!("current layer: {new}");
+            if tmpfile {
+                let mut tmp = std::env::temp_dir();
+                tmp.push("kanata_layer");
+                if let Err(error) = std::fs::write(tmp, new) {
+                    log::error!("failed to write tmpfile: {error}");
+                }
+            }
+        }
+        ServerOutput::Message(ServerMessage::LayerNames { names }) => {
+            log::info!("kanata layers: {}", names.join(", "));
+            warn_unknown_layers(engine, names);
+        }
+        ServerOutput::Message(ServerMessage::Error { msg })
+        | ServerOutput::Response(ServerResponse::Error { msg }) => {
+            log::error!("kanata reported an error: {msg}");
+        }
+        ServerOutput::Message(message) => log::debug!("kanata sent {message:?}"),
+        ServerOutput::Response(ServerResponse::Ok) => {}
+    }
+}
+
+/// Warns about layers used by the configuration which kanata doesn't define
+fn warn_unknown_layers(engine: &RuleEngine, kanata_layers: &[String]) {
+    for problem in validation::validate(
+        engine.configuration(),
+        Some(kanata_layers),
+        engine.default_layer(),
+    ) {
+        if matches!(
+            problem,
+            Problem::UnknownLayer { .. } | Problem::UnknownDefaultLayer { .. }
+        ) {
+            log::warn!("{problem}");
+        }
+    }
+}
+
+fn handle_event(
+    engine: &RuleEngine,
+    kanata: &Connection,
+    lock: &Mutex<Option<Lock>>,
+    event: Event,
+    hwnd: isize,
+    window: &WindowInfo,
+    explain: bool,
+) -> Result<()> {
+    // A layer change which is still settling was decided for a window which
+    // no longer has focus, so it is cancelled even when the layer won't change
+    if locked(lock, event, hwnd) {
+        return kanata.settle_layer(None, Duration::ZERO);
+    }
+
+    let decision = decide(engine, kanata, event, window, explain);
+
+    // Without a layer, this also cancels any layer change which is settling
+    kanata.settle_layer(decision.layer.as_deref(), engine.settle_delay(&decision))
+}
+
+/// Decides which layer kanata should be on for the window, if any
+fn decide(
+    engine: &RuleEngine,
+    kanata: &Connection,
+    event: Event,
+    window: &WindowInfo,
+    explain: bool,
+) -> Decision {
+    let mut decision = engine.decide(event, window, &WindowsKeyState);
+
+    if explain {
+        log::info!(
+            "{event:?} for {} ({}): {decision}",
+            window.exe,
+            window.title
+        );
+    }
+
+    // kanata ignores requests to change to a layer it doesn't have
+    if let (Some(layer), Some(known)) = (&decision.layer, kanata.state().layer_names) {
+        if !known.contains(layer) {
+            let default = engine
+                .default_layer()
+                .filter(|default| known.iter().any(|known| known == default));
+
+            log::warn!(
+                "kanata doesn't define layer {layer:?}, using the default layer {default:?} instead"
+            );
+
+            decision.layer = default.map(String::from);
+        }
+    }
+
+    decision
+}
+
+impl TryFrom<&Window> for WindowInfo {
+    type Error = Report;
+
+    fn try_from(window: &Window) -> Result<Self> {
+        Ok(Self {
+            exe: window.exe()?,
+            title: window.title()?,
+            class: window.class()?,
+        })
+    }
+}
@@ -0,0 +1,1144 @@
+#![allow(clippy::use_self)]
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::configuration::Configuration;
+use crate::configuration::Resolution;
+use crate::configuration::TestCase;
+use crate::key_state::InMemoryKeyState;
+use crate::key_state::KeyState;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ValueEnum, JsonSchema)]
+pub enum Event {
+    Show,
+    FocusChange,
+}
+
+/// The properties of a window which rules can be matched against
+#[derive(Debug, Clone)]
+pub struct WindowInfo {
+    pub exe: String,
+    pub title: String,
+    pub class: String,
+}
+
+/// The rule which contributed a candidate layer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Entry,
+    TitleOverride(usize),
+    ClassOverride(usize),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Entry => write!(f, "target layer"),
+            Source::TitleOverride(i) => write!(f, "title override {i}"),
+            Source::ClassOverride(i) => write!(f, "class override {i}"),
+        }
+    }
+}
+
+/// A single check made while calculating the target layer
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// An entry's exe and `when` condition were tested against the window
+    Entry {
+        entry: usize,
+        exe: String,
+        matched: bool,
+        branch: Option<String>,
+    },
+    /// A title or class override of a matching entry was tested
+    Override {
+        entry: usize,
+        source: Source,
+        rule: String,
+        matched: bool,
+        branch: Option<String>,
+        target_layer: String,
+    },
+    /// A virtual key override (with a target layer) or a virtual key ignore
+    /// (without one) of a matching entry was checked
+    Key {
+        entry: usize,
+        virtual_key_code: i32,
+        held: bool,
+        target_layer: Option<String>,
+    },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Entry {
+                entry,
+                exe,
+                matched,
+                branch,
+            } => {
+                write!(f, "entry {entry} ({exe}): ")?;
+                match (matched, branch) {
+                    (true, Some(branch)) => write!(f, "matched on {branch}"),
+                    (true, None) => write!(f, "matched"),
+                    (false, _) => write!(f, "no match"),
+                }
+            }
+            Step::Override {
+                entry,
+                source,
+                rule,
+                matched,
+                branch,
+                target_layer,
+            } => {
+                write!(f, "entry {entry} {source} ({rule} -> {target_layer}): ")?;
+                match (matched, branch) {
+                    (true, Some(branch)) => write!(f, "matched on {branch}"),
+                    (true, None) => write!(f, "matched"),
+                    (false, _) => write!(f, "no match"),
+                }
+            }
+            Step::Key {
+                entry,
+                virtual_key_code,
+                held,
+                target_layer,
+            } => {
+                match target_layer {
+                    Some(target_layer) => write!(
+                        f,
+                        "entry {entry} virtual key override ({virtual_key_code} -> {target_layer}): "
+                    )?,
+                    None => write!(f, "entry {entry} virtual key ignore ({virtual_key_code}): ")?,
+                }
+
+                write!(f, "{}", if *held { "held" } else { "not held" })
+            }
+        }
+    }
+}
+
+/// Why a decision ended up with the layer that it did
+#[derive(Debug, Clone)]
+pub enum Reason {
+    /// A virtual key ignore was held, so no layer change should be made
+    KeyIgnored { entry: usize, virtual_key_code: i32 },
+    /// A virtual key override was held, which takes precedence over everything
+    KeyOverride { entry: usize, virtual_key_code: i32 },
+    /// A matching rule was picked using the configured resolution mode
+    Resolved {
+        resolution: Resolution,
+        entry: usize,
+        source: Source,
+    },
+    /// No rules matched, so the default layer was used
+    Default,
+    /// No rules matched and there is no default layer for this event
+    NoMatch,
+}
+
+impl Reason {
+    /// The entry which decided the layer, if any
+    #[must_use]
+    pub const fn entry(&self) -> Option<usize> {
+        match self {
+            Reason::KeyIgnored { entry, .. }
+            | Reason::KeyOverride { entry, .. }
+            | Reason::Resolved { entry, .. } => Some(*entry),
+            Reason::Default | Reason::NoMatch => None,
+        }
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::KeyIgnored {
+                entry,
+                virtual_key_code,
+            } => write!(
+                f,
+                "virtual key {virtual_key_code} is ignored by entry {entry}"
+            ),
+            Reason::KeyOverride {
+                entry,
+                virtual_key_code,
+            } => write!(
+                f,
+                "virtual key {virtual_key_code} is overridden by entry {entry}"
+            ),
+            Reason::Resolved {
+                resolution,
+                entry,
+                source,
+            } => write!(f, "entry {entry} {source} won using {resolution}"),
+            Reason::Default => write!(f, "no rules matched, using the default layer"),
+            Reason::NoMatch => write!(f, "no rules matched"),
+        }
+    }
+}
+
+/// The target layer for a window, along with a trace of how it was calculated
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub layer: Option<String>,
+    pub steps: Vec<Step>,
+    pub reason: Reason,
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.layer {
+            Some(layer) => write!(f, "layer {layer}")?,
+            None => write!(f, "no layer change")?,
+        }
+
+        write!(f, " ({})", self.reason)?;
+
+        for step in &self.steps {
+            write!(f, "\n  {step}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Selects kanata layers for windows based on a [`Configuration`]
+#[derive(Debug, Clone)]
+pub struct RuleEngine {
+    configuration: Configuration,
+    default_layer: Option<String>,
+}
+
+impl RuleEngine {
+    /// The default layer is used for focused windows which don't match any rules,
+    /// and takes precedence over the `default_layer` in the configuration.
+    ///
+    /// Fails if any of the regexes used by the rules are invalid.
+    pub fn new(mut configuration: Configuration, default_layer: Option<String>) -> Result<Self> {
+        configuration.compile()?;
+        let default_layer = default_layer.or_else(|| configuration.default_layer.clone());
+
+        Ok(Self {
+            configuration,
+            default_layer,
+        })
+    }
+
+    #[must_use]
+    pub const fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+
+    #[must_use]
+    pub fn default_layer(&self) -> Option<&str> {
+        self.default_layer.as_deref()
+    }
+
+    /// Calculates the target layer for a window, along with a trace of how it was reached
+    #[must_use]
+    pub fn decide(&self, event: Event, window: &WindowInfo, key_state: &dyn KeyState) -> Decision {
+        let default = if matches!(event, Event::FocusChange) {
+            self.default_layer()
+        } else {
+            None
+        };
+
+        calculate_decision(&self.configuration, event, window, default, key_state)
+    }
+
+    /// How long focus has to rest on a window before the layer decided for it
+    /// is sent, taking the entry which decided the layer into account
+    #[must_use]
+    pub fn settle_delay(&self, decision: &Decision) -> Duration {
+        let entry = decision
+            .reason
+            .entry()
+            .and_then(|entry| self.configuration.rules.get(entry))
+            .and_then(|entry| entry.settle_delay_ms);
+
+        Duration::from_millis(
+            entry
+                .or(self.configuration.settle_delay_ms)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Calculates the target layer for a window, or `None` if the layer shouldn't change
+    #[must_use]
+    pub fn target(
+        &self,
+        event: Event,
+        window: &WindowInfo,
+        key_state: &dyn KeyState,
+    ) -> Option<String> {
+        self.decide(event, window, key_state).layer
+    }
+
+    /// Runs every test case in the configuration against the rules
+    #[must_use]
+    pub fn run_tests(&self) -> Vec<TestResult> {
+        self.configuration
+            .tests
+            .iter()
+            .map(|case| {
+                let window = WindowInfo {
+                    exe: case.exe.clone(),
+                    title: case.title.clone(),
+                    class: case.class.clone(),
+                };
+
+                let key_state = case
+                    .held_virtual_keys
+                    .iter()
+                    .copied()
+                    .collect::<InMemoryKeyState>();
+
+                TestResult {
+                    case: case.clone(),
+                    decision: self.decide(case.event, &window, &key_state),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The outcome of running one of the configuration's test cases
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub case: TestCase,
+    pub decision: Decision,
+}
+
+impl TestResult {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.decision.layer == self.case.expected_layer
+    }
+}
+
+/// A layer which a matching entry or override would switch to
+#[derive(Debug, Copy, Clone)]
+struct Candidate<'a> {
+    layer: &'a str,
+    priority: i32,
+    entry: usize,
+    source: Source,
+}
+
+/// The candidates contributed by a single matching entry
+struct EntryMatch<'a> {
+    base: Candidate<'a>,
+    overrides: Vec<Candidate<'a>>,
+}
+
+#[allow(clippy::too_many_lines)]
+fn calculate_decision(
+    configuration: &Configuration,
+    event: Event,
+    window: &WindowInfo,
+    default: Option<&str>,
+    key_state: &dyn KeyState,
+) -> Decision {
+    let mut steps = vec![];
+    let mut entry_matches = vec![];
+    let mut key_override = None;
+    let mut key_ignored = None;
+
+    for (i, entry) in configuration.rules.iter().enumerate() {
+        let exe_matched = entry.matches_exe(&window.exe);
+        let when = entry
+            .when
+            .as_ref()
+            .filter(|_| exe_matched)
+            .map(|condition| condition.evaluate(window, key_state));
+
+        // An absent when condition always holds
+        let matched = exe_matched && when.as_ref().is_none_or(Option::is_some);
+
+        steps.push(Step::Entry {
+            entry: i,
+            exe: entry.exe.clone(),
+            matched,
+            branch: when.flatten(),
+        });
+
+        if !matched {
+            continue;
+        }
+
+        // With last_match, a later matching entry replaces the held keys of
+        // earlier entries along with their layers, like the original
+        // komokana did. Otherwise held keys of any matching entry take
+        // precedence over the resolved layer.
+        if matches!(configuration.resolution(), Resolution::LastMatch)
+            && matches!(event, Event::FocusChange)
+        {
+            key_override = None;
+            key_ignored = None;
+        }
+
+        let base = Candidate {
+            layer: &entry.target_layer,
+            priority: entry.priority.unwrap_or(0),
+            entry: i,
+            source: Source::Entry,
+        };
+
+        let mut overrides = vec![];
+
+        if let Some(class_overrides) = &entry.class_overrides {
+            for (j, class_override) in class_overrides.iter().enumerate() {
+                let when = class_override
+                    .when
+                    .as_ref()
+                    .map(|condition| condition.evaluate(window, key_state));
+
+                let matched = class_override.matches(&window.class)
+                    && when.as_ref().is_none_or(Option::is_some);
+
+                steps.push(Step::Override {
+                    entry: i,
+                    source: Source::ClassOverride(j),
+                    rule: class_override.to_string(),
+                    matched,
+                    branch: when.flatten(),
+                    target_layer: class_override.target_layer.clone(),
+                });
+
+                if matched {
+                    overrides.push(Candidate {
+                        layer: &class_override.target_layer,
+                        priority: class_override.priority.unwrap_or(base.priority),
+                        entry: i,
+                        source: Source::ClassOverride(j),
+                    });
+                }
+            }
+        }
+
+        if let Some(title_overrides) = &entry.title_overrides {
+            for (j, title_override) in title_overrides.iter().enumerate() {
+                let when = title_override
+                    .when
+                    .as_ref()
+                    .map(|condition| condition.evaluate(window, key_state));
+
+                let matched = title_override.matches(&window.title)
+                    && when.as_ref().is_none_or(Option::is_some);
+
+                steps.push(Step::Override {
+                    entry: i,
+                    source: Source::TitleOverride(j),
+                    rule: title_override.to_string(),
+                    matched,
+                    branch: when.flatten(),
+                    target_layer: title_override.target_layer.clone(),
+                });
+
+                if matched {
+                    overrides.push(Candidate {
+                        layer: &title_override.target_layer,
+                        priority: title_override.priority.unwrap_or(base.priority),
+                        entry: i,
+                        source: Source::TitleOverride(j),
+                    });
+                }
+            }
+        }
+
+        // On Show events, entries only apply when they have overrides, in
+        // which case the entry's target layer acts as a default
+        if matches!(event, Event::FocusChange)
+            || entry.title_overrides.is_some()
+            || entry.class_overrides.is_some()
+        {
+            entry_matches.push(EntryMatch { base, overrides });
+        }
+
+        if matches!(event, Event::FocusChange) {
+            if let Some(virtual_key_overrides) = &entry.virtual_key_overrides {
+                for virtual_key_override in virtual_key_overrides {
+                    let held = key_state.is_held(virtual_key_override.virtual_key_code);
+
+                    steps.push(Step::Key {
+                        entry: i,
+                        virtual_key_code: virtual_key_override.virtual_key_code,
+                        held,
+                        target_layer: Option::from(virtual_key_override.target_layer.clone()),
+                    });
+
+                    if held {
+                        key_override = Option::from((i, virtual_key_override));
+                    }
+                }
+            }
+
+            if let Some(virtual_key_ignores) = &entry.virtual_key_ignores {
+                for virtual_key in virtual_key_ignores {
+                    let held = key_state.is_held(*virtual_key);
+
+                    steps.push(Step::Key {
+                        entry: i,
+                        virtual_key_code: *virtual_key,
+                        held,
+                        target_layer: None,
+                    });
+
+                    if held {
+                        key_ignored = Option::from((i, *virtual_key));
+                    }
+                }
+            }
+        }
+    }
+
+    let (layer, reason) = if let Some((entry, virtual_key_code)) = key_ignored {
+        (
+            None,
+            Reason::KeyIgnored {
+                entry,
+                virtual_key_code,
+            },
+        )
+    } else if let Some((entry, virtual_key_override)) = key_override {
+        (
+            Option::from(virtual_key_override.target_layer.as_str()),
+            Reason::KeyOverride {
+                entry,
+                virtual_key_code: virtual_key_override.virtual_key_code,
+            },
+        )
+    } else if let Some(winner) = resolve(configuration.resolution(), event, &entry_matches) {
+        (
+            Option::from(winner.layer),
+            Reason::Resolved {
+                resolution: configuration.resolution(),
+                entry: winner.entry,
+                source: winner.source,
+            },
+        )
+    } else if default.is_some() {
+        (default, Reason::Default)
+    } else {
+        (None, Reason::NoMatch)
+    };
+
+    Decision {
+        layer: layer.map(ToString::to_string),
+        steps,
+        reason,
+    }
+}
+
+fn resolve<'a>(
+    resolution: Resolution,
+    event: Event,
+    entry_matches: &[EntryMatch<'a>],
+) -> Option<Candidate<'a>> {
+    match resolution {
+        Resolution::LastMatch => {
+            let mut winner = None;
+            for entry_match in entry_matches {
+                if let Some(last) = entry_match.overrides.last() {
+                    winner = Option::from(*last);
+                } else if matches!(event, Event::FocusChange) || winner.is_none() {
+                    winner = Option::from(entry_match.base);
+                }
+            }
+
+            winner
+        }
+        Resolution::FirstMatch => entry_matches.first().map(|entry_match| {
+            entry_match
+                .overrides
+                .first()
+                .copied()
+                .unwrap_or(entry_match.base)
+        }),
+        Resolution::Priority => {
+            let candidates = entry_matches
+                .iter()
+                .flat_map(|entry_match| {
+                    std::iter::once(&entry_match.base).chain(&entry_match.overrides)
+                })
+                .collect::<Vec<_>>();
+
+            // max_by_key returns the last of equally prioritised candidates,
+            // which always puts overrides ahead of their own entry
+            let winner = candidates
+                .iter()
+                .max_by_key(|candidate| candidate.priority)
+                .copied()
+                .copied();
+
+            if let Some(winner) = winner {
+                for candidate in &candidates {
+                    if candidate.priority == winner.priority
+                        && candidate.layer != winner.layer
+                        && candidate.entry != winner.entry
+                    {
+                        log::debug!(
+                            "entries {} and {} both match with priority {}, picking {}",
+                            candidate.entry,
+                            winner.entry,
+                            winner.priority,
+                            winner.layer
+                        );
+                    }
+                }
+            }
+
+            winner
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::parse;
+    use crate::configuration::Format;
+    use crate::key_state::InMemoryKeyState;
+    use crate::key_state::NoKeyState;
+
+    fn rule_engine(yaml: &str) -> RuleEngine {
+        RuleEngine::new(
+            parse(yaml, Format::Yaml).unwrap(),
+            Some(String::from("base")),
+        )
+        .unwrap()
+    }
+
+    fn window(exe: &str, title: &str, class: &str) -> WindowInfo {
+        WindowInfo {
+            exe: exe.to_string(),
+            title: title.to_string(),
+            class: class.to_string(),
+        }
+    }
+
+    fn target(
+        engine: &RuleEngine,
+        event: Event,
+        window: &WindowInfo,
+        held: &[i32],
+    ) -> Option<String> {
+        let key_state = held.iter().copied().collect::<InMemoryKeyState>();
+        engine.target(event, window, &key_state)
+    }
+
+    const FIREFOX: &str = r#"
+- exe: "firefox.exe"
+  target_layer: "firefox"
+  title_overrides:
+    - title: "Slack |"
+      strategy: "starts_with"
+      target_layer: "slack"
+    - title: "Slack | general"
+      strategy: "equals"
+      target_layer: "general"
+  virtual_key_overrides:
+    - virtual_key_code: 18
+      targer_layer: "alt"
+  virtual_key_ignores: [17]
+- exe: "Code.exe"
+  target_layer: "editor"
+"#;
+
+    #[test]
+    fn last_match_is_the_default_resolution() {
+        let engine = rule_engine(FIREFOX);
+
+        for (title, expected) in [
+            ("Mozilla Firefox", "firefox"),
+            ("Slack | random", "slack"),
+            ("Slack | general", "general"),
+        ] {
+            assert_eq!(
+                target(
+                    &engine,
+                    Event::FocusChange,
+                    &window("firefox.exe", title, ""),
+                    &[]
+                )
+                .as_deref(),
+                Some(expected),
+                "{title}"
+            );
+        }
+
+        let later = rule_engine(
+            r#"
+- exe: "firefox.exe"
+  target_layer: "firefox"
+- exe: "firefox.exe"
+  target_layer: "later"
+"#,
+        );
+
+        assert_eq!(
+            target(
+                &later,
+                Event::FocusChange,
+                &window("firefox.exe", "", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("later")
+        );
+    }
+
+    #[test]
+    fn default_layer_is_only_used_for_focus_changes() {
+        let engine = rule_engine(FIREFOX);
+        let unknown = window("explorer.exe", "", "");
+
+        assert_eq!(
+            target(&engine, Event::FocusChange, &unknown, &[]).as_deref(),
+            Some("base")
+        );
+        assert_eq!(target(&engine, Event::Show, &unknown, &[]), None);
+    }
+
+    #[test]
+    fn show_events_only_apply_entries_with_overrides() {
+        let engine = rule_engine(FIREFOX);
+
+        for (window, expected) in [
+            (window("Code.exe", "", ""), None),
+            (window("firefox.exe", "Slack | random", ""), Some("slack")),
+            (
+                window("firefox.exe", "Mozilla Firefox", ""),
+                Some("firefox"),
+            ),
+        ] {
+            assert_eq!(
+                target(&engine, Event::Show, &window, &[]).as_deref(),
+                expected,
+                "{}",
+                window.exe
+            );
+        }
+    }
+
+    #[test]
+    fn key_ignores_take_precedence_over_key_overrides() {
+        let engine = rule_engine(FIREFOX);
+        let slack = window("firefox.exe", "Slack | random", "");
+
+        assert_eq!(
+            target(&engine, Event::FocusChange, &slack, &[18]).as_deref(),
+            Some("alt")
+        );
+        assert_eq!(target(&engine, Event::FocusChange, &slack, &[17, 18]), None);
+
+        // Held keys are only taken into account for focus changes
+        assert_eq!(
+            target(&engine, Event::Show, &slack, &[17, 18]).as_deref(),
+            Some("slack")
+        );
+    }
+
+    #[test]
+    fn later_entries_replace_the_held_keys_of_earlier_entries() {
+        let rules = r#"
+  - exe: "a.exe"
+    target_layer: "a"
+    virtual_key_ignores: [17]
+  - exe: "a.exe"
+    target_layer: "b"
+"#;
+        let a = window("a.exe", "", "");
+
+        let engine = rule_engine(&format!("rules:{rules}"));
+        assert_eq!(
+            target(&engine, Event::FocusChange, &a, &[17]).as_deref(),
+            Some("b")
+        );
+
+        // Other resolution modes let held keys of any matching entry win
+        for resolution in ["first_match", "priority"] {
+            let engine = rule_engine(&format!("resolution: {resolution}\nrules:{rules}"));
+            assert_eq!(
+                target(&engine, Event::FocusChange, &a, &[17]),
+                None,
+                "{resolution}"
+            );
+        }
+    }
+
+    #[test]
+    fn regex_overrides_match_titles() {
+        let engine = rule_engine(
+            r#"
+- exe: "Code.exe"
+  target_layer: "editor"
+  title_overrides:
+    - title: "^main\\.rs"
+      strategy: "regex"
+      target_layer: "rust"
+    - title: "readme"
+      strategy: "regex"
+      target_layer: "docs"
+      case_sensitive: false
+"#,
+        );
+
+        for (title, expected) in [
+            ("main.rs - komokana", "rust"),
+            ("lib.rs - main.rs", "editor"),
+            ("Main.rs - komokana", "editor"),
+            ("README.md - komokana", "docs"),
+        ] {
+            assert_eq!(
+                target(
+                    &engine,
+                    Event::FocusChange,
+                    &window("Code.exe", title, ""),
+                    &[]
+                )
+                .as_deref(),
+                Some(expected),
+                "{title}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_regexes_are_rejected_on_load() {
+        let error = parse(
+            "- exe: a.exe\n  target_layer: a\n  title_overrides:\n    - title: \"(\"\n      strategy: regex\n      target_layer: b\n",
+            Format::Yaml,
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("invalid regex"), "{error}");
+    }
+
+    #[test]
+    fn regexes_are_compiled_for_deserialized_configurations() {
+        let configuration = Format::Yaml
+            .deserialize(
+                r#"
+rules:
+  - exe: "Code.exe"
+    target_layer: "editor"
+    title_overrides:
+      - title: "^main"
+        strategy: "regex"
+        target_layer: "regex"
+"#,
+            )
+            .unwrap();
+
+        let engine = RuleEngine::new(configuration, None).unwrap();
+
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("Code.exe", "main.rs", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("regex")
+        );
+    }
+
+    #[test]
+    fn matching_folds_case_and_normalizes_unicode() {
+        let engine = rule_engine(
+            r#"
+- exe: "Straße.exe"
+  target_layer: "folded"
+  title_overrides:
+    - title: "Café"
+      strategy: "equals"
+      target_layer: "cafe"
+- exe: "Code.exe"
+  target_layer: "editor"
+  case_sensitive: true
+"#,
+        );
+
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("STRASSE.EXE", "", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("folded")
+        );
+        // Titles are compared case sensitively by default, but always in NFC form
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("strasse.exe", "Cafe\u{301}", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("cafe")
+        );
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("strasse.exe", "CAFÉ", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("folded")
+        );
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("code.exe", "", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("base")
+        );
+    }
+
+    #[test]
+    fn exe_case_sensitivity_is_not_inherited_by_overrides() {
+        let engine = rule_engine(
+            r#"
+- exe: "firefox.exe"
+  target_layer: "firefox"
+  case_sensitive: false
+  title_overrides:
+    - title: "Slack |"
+      strategy: "starts_with"
+      target_layer: "slack"
+- exe: "javaw.exe"
+  target_layer: "java"
+  case_sensitive: true
+  class_overrides:
+    - class: "sunawtframe"
+      strategy: "equals"
+      target_layer: "ide"
+"#,
+        );
+
+        for (window, expected) in [
+            (window("Firefox.exe", "Slack | general", ""), "slack"),
+            (window("Firefox.exe", "SLACK | general", ""), "firefox"),
+            (window("javaw.exe", "", "SunAwtFrame"), "ide"),
+            (window("Javaw.exe", "", "SunAwtFrame"), "base"),
+        ] {
+            assert_eq!(
+                target(&engine, Event::FocusChange, &window, &[]).as_deref(),
+                Some(expected),
+                "{} {}",
+                window.exe,
+                window.title
+            );
+        }
+    }
+
+    #[test]
+    fn title_overrides_win_over_class_overrides() {
+        let engine = rule_engine(
+            r#"
+- exe: "javaw.exe"
+  target_layer: "java"
+  class_overrides:
+    - class: "sunawtframe"
+      strategy: "equals"
+      target_layer: "ide"
+  title_overrides:
+    - title: "Settings"
+      strategy: "contains"
+      target_layer: "settings"
+"#,
+        );
+
+        for (title, class, expected) in [
+            ("Project", "SunAwtFrame", "ide"),
+            ("Settings", "SunAwtFrame", "settings"),
+            ("Project", "SunAwtDialog", "java"),
+        ] {
+            assert_eq!(
+                target(
+                    &engine,
+                    Event::FocusChange,
+                    &window("javaw.exe", title, class),
+                    &[]
+                )
+                .as_deref(),
+                Some(expected),
+                "{title} {class}"
+            );
+        }
+    }
+
+    #[test]
+    fn when_conditions_must_hold_for_entries_to_match() {
+        let engine = rule_engine(
+            r#"
+- exe: "firefox.exe"
+  target_layer: "pip"
+  when:
+    all:
+      - title:
+          value: "Picture-in-Picture"
+      - not:
+          key_held: 18
+"#,
+        );
+        let pip = window("firefox.exe", "Picture-in-Picture", "");
+
+        assert_eq!(
+            target(
+                &engine,
+                Event::FocusChange,
+                &window("firefox.exe", "Mozilla Firefox", ""),
+                &[]
+            )
+            .as_deref(),
+            Some("base")
+        );
+
+        assert_eq!(
+            target(&engine, Event::FocusChange, &pip, &[]).as_deref(),
+            Some("pip")
+        );
+        assert_eq!(
+            target(&engine, Event::FocusChange, &pip, &[18]).as_deref(),
+            Some("base")
+        );
+    }
+
+    #[test]
+    fn resolution_modes_pick_different_winners() {
+        let rules = r#"
+rules:
+  - exe: "firefox.exe"
+    target_layer: "first"
+    priority: 10
+    title_overrides:
+      - title: "Slack |"
+        strategy: "starts_with"
+        target_layer: "slack"
+        priority: 20
+  - exe: "firefox.exe"
+    target_layer: "second"
+"#;
+        let slack = window("firefox.exe", "Slack | general", "");
+        let firefox = window("firefox.exe", "Mozilla Firefox", "");
+
+        for (resolution, expected_slack, expected_firefox) in [
+            ("last_match", "second", "second"),
+            ("first_match", "slack", "first"),
+            ("priority", "slack", "first"),
+        ] {
+            let engine = rule_engine(&format!("resolution: {resolution}\n{rules}"));
+
+            assert_eq!(
+                target(&engine, Event::FocusChange, &slack, &[]).as_deref(),
+                Some(expected_slack),
+                "{resolution}"
+            );
+            assert_eq!(
+                target(&engine, Event::FocusChange, &firefox, &[]).as_deref(),
+                Some(expected_firefox),
+                "{resolution}"
+            );
+        }
+    }
+
+    #[test]
+    fn decisions_explain_which_rule_won() {
+        let engine = rule_engine(
+            r#"
+- exe: "firefox.exe"
+  target_layer: "firefox"
+  title_overrides:
+    - title: "Slack |"
+      strategy: "starts_with"
+      target_layer: "slack"
+"#,
+        );
+
+        let decision = engine.decide(
+            Event::FocusChange,
+            &window("firefox.exe", "Slack | general", ""),
+            &NoKeyState,
+        );
+
+        assert_eq!(decision.layer.as_deref(), Some("slack"));
+        assert!(matches!(
+            decision.reason,
+            Reason::Resolved {
+                resolution: Resolution::LastMatch,
+                entry: 0,
+                source: Source::TitleOverride(0),
+            }
+        ));
+        assert_eq!(
+            decision.to_string(),
+            "layer slack (entry 0 title override 0 won using last_match)\n  \
+             entry 0 (firefox.exe): matched\n  \
+             entry 0 title override 0 (title starts_with \"Slack |\" -> slack): matched"
+        );
+
+        let decision = engine.decide(Event::Show, &window("Code.exe", "", ""), &NoKeyState);
+
+        assert_eq!(decision.layer, None);
+        assert!(matches!(decision.reason, Reason::NoMatch));
+        assert_eq!(
+            decision.to_string(),
+            "no layer change (no rules matched)\n  entry 0 (firefox.exe): no match"
+        );
+    }
+
+    #[test]
+    fn test_cases_are_run_with_their_held_keys() {
+        let rules = r#"
+rules:
+  - exe: "firefox.exe"
+    target_layer: "firefox"
+    virtual_key_ignores: [18]
+tests:
+  - exe: firefox.exe
+    event: FocusChange
+    expected_layer: firefox
+  - exe: explorer.exe
+    event: FocusChange
+    expected_layer: base
+  - exe: explorer.exe
+    event: Show
+    expected_layer: null
+  - name: alt is ignored
+    exe: firefox.exe
+    event: FocusChange
+    held_virtual_keys: [18]
+    expected_layer: firefox
+"#;
+        let engine = rule_engine(rules);
+        let results = engine.run_tests();
+
+        assert_eq!(
+            results.iter().map(TestResult::passed).collect::<Vec<_>>(),
+            [true, true, true, false]
+        );
+        assert_eq!(results[3].case.to_string(), "alt is ignored");
+        assert_eq!(results[3].decision.layer, None);
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+
+use color_eyre::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Messages which kanata's TCP server sends to its clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// The active layer changed, for any reason
+    LayerChange {
+        new: String,
+    },
+    /// The response to [`ClientMessage::RequestLayerNames`]
+    LayerNames {
+        names: Vec<String>,
+    },
+    /// The response to [`ClientMessage::RequestCurrentLayerInfo`]
+    CurrentLayerInfo {
+        name: String,
+        cfg_text: String,
+    },
+    /// kanata loaded a configuration file
+    ConfigFileReload {
+        new: String,
+    },
+    /// The response to [`ClientMessage::RequestCurrentLayerName`]
+    CurrentLayerName {
+        name: String,
+    },
+    /// A message pushed by a `push-msg` action in the kanata configuration
+    MessagePush {
+        message: serde_json::Value,
+    },
+    Error {
+        msg: String,
+    },
+}
+
+/// The status which newer versions of kanata send in response to a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status")]
+pub enum ServerResponse {
+    Ok,
+    Error { msg: String },
+}
+
+/// A single line of output from kanata's TCP server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ServerOutput {
+    Message(ServerMessage),
+    Response(ServerResponse),
+}
+
+/// Messages which clients can send to kanata's TCP server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientMessage {
+    ChangeLayer { new: String },
+    RequestLayerNames {},
+    RequestCurrentLayerInfo {},
+    RequestCurrentLayerName {},
+    ActOnFakeKey { name: String, action: FakeKeyAction },
+    SetMouse { x: u16, y: u16 },
+    Reload {},
+    ReloadNext {},
+    ReloadPrev {},
+    ReloadNum { index: usize },
+    ReloadFile { path: String },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FakeKeyAction {
+    Press,
+    Release,
+    Tap,
+    Toggle,
+}
+
+/// Reads the newline-delimited output of kanata's TCP server, so that
+/// messages are handled correctly however they are split across reads
+#[derive(Debug)]
+pub struct ServerMessages<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> ServerMessages<R> {
+    pub const fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+        }
+    }
+
+    /// Reads the next message, or `None` once kanata has closed the connection.
+    ///
+    /// Lines which aren't valid messages are consumed and reported as
+    /// [`std::io::ErrorKind::InvalidData`] errors, after which reading can
+    /// continue with the next line.
+    pub fn next_message(&mut self) -> std::io::Result<Option<ServerOutput>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+
+            let line = self.line.trim();
+            if !line.is_empty() {
+                return serde_json::from_str(line)
+                    .map(Some)
+                    .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error));
+            }
+        }
+    }
+}
+
+/// A connection to kanata's TCP server
+#[derive(Debug)]
+pub struct KanataClient {
+    stream: TcpStream,
+}
+
+impl KanataClient {
+    pub fn connect(port: i32) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect(format!("localhost:{port}"))?,
+        })
+    }
+
+    /// Creates another handle to the same connection, e.g. for a read thread
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+        })
+    }
+
+    /// Closes the connection, including any other handles to it
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    /// Asks kanata to switch to the given layer
+    pub fn change_layer(&mut self, layer: &str) -> Result<()> {
+        self.send(&ClientMessage::ChangeLayer {
+            new: layer.to_string(),
+        })
+    }
+
+    /// Asks kanata for the names of its layers, which it sends back as a
+    /// `LayerNames` message on this connection
+    pub fn request_layer_names(&mut self) -> Result<()> {
+        self.send(&ClientMessage::RequestLayerNames {})
+    }
+
+    /// Sends a message to kanata, terminated by a newline like every message
+    /// in kanata's protocol
+    pub fn send(&mut self, message: &ClientMessage) -> Result<()> {
+        let request = serde_json::to_string(message)?;
+        self.stream.write_all(format!("{request}\n").as_bytes())?;
+        log::debug!("request sent: {request}");
+
+        Ok(())
+    }
+}
+
+impl Read for KanataClient {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
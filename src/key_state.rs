@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+
+#[cfg(windows)]
+use windows::Win32::UI::Input::KeyboardAndMouse::GetKeyState;
+
+/// A source of virtual key states which rules can be evaluated against
+pub trait KeyState {
+    /// Whether the virtual key with this code is currently held down
+    fn is_held(&self, virtual_key_code: i32) -> bool;
+}
+
+/// Polls the state of the keyboard with `GetKeyState`
+#[cfg(windows)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct WindowsKeyState;
+
+#[cfg(windows)]
+impl KeyState for WindowsKeyState {
+    fn is_held(&self, virtual_key_code: i32) -> bool {
+        unsafe { GetKeyState(virtual_key_code) < 0 }
+    }
+}
+
+/// A fixed set of held keys, for tests and replays
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InMemoryKeyState {
+    held: HashSet<i32>,
+}
+
+impl FromIterator<i32> for InMemoryKeyState {
+    fn from_iter<T: IntoIterator<Item = i32>>(iter: T) -> Self {
+        Self {
+            held: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl KeyState for InMemoryKeyState {
+    fn is_held(&self, virtual_key_code: i32) -> bool {
+        self.held.contains(&virtual_key_code)
+    }
+}
+
+/// For platforms without key polling, where no keys are ever held
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoKeyState;
+
+impl KeyState for NoKeyState {
+    fn is_held(&self, _virtual_key_code: i32) -> bool {
+        false
+    }
+}
+
+/// The key state source for the current platform
+#[cfg(windows)]
+pub type SystemKeyState = WindowsKeyState;
+
+/// The key state source for the current platform
+#[cfg(not(windows))]
+pub type SystemKeyState = NoKeyState;
@@ -0,0 +1,67 @@
+#![warn(clippy::all, clippy::nursery, clippy::pedantic)]
+#![allow(clippy::missing_errors_doc)]
+
+//! The rule engine, configuration types and kanata client behind the
+//! `komokana` daemon, which can be embedded in other tools.
+//!
+//! The public API of this crate follows semantic versioning.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use color_eyre::eyre::anyhow;
+use color_eyre::Result;
+
+pub mod configuration;
+pub mod connection;
+#[cfg(windows)]
+pub mod daemon;
+pub mod engine;
+pub mod kanata;
+pub mod key_state;
+pub mod lock;
+pub mod migration;
+pub mod reconnect;
+pub mod validation;
+
+pub use configuration::Configuration;
+pub use engine::Decision;
+pub use engine::Event;
+pub use engine::RuleEngine;
+pub use engine::WindowInfo;
+pub use kanata::KanataClient;
+pub use key_state::KeyState;
+
+pub fn resolve_windows_path(raw_path: &str) -> Result<PathBuf> {
+    let path = if raw_path.starts_with('~') {
+        raw_path.replacen(
+            '~',
+            &dirs::home_dir()
+                .ok_or_else(|| anyhow!("there is no home directory"))?
+                .display()
+                .to_string(),
+            1,
+        )
+    } else {
+        raw_path.to_string()
+    };
+
+    let full_path = PathBuf::from(path);
+
+    // A bare file name has an empty parent, which is the current directory
+    let parent = match full_path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return Err(anyhow!("cannot parse directory")),
+    };
+
+    let file = full_path
+        .components()
+        .next_back()
+        .ok_or_else(|| anyhow!("cannot parse filename"))?;
+
+    let mut canonicalized = std::fs::canonicalize(parent)?;
+    canonicalized.push(file);
+
+    Ok(canonicalized)
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::configuration::ManualLock;
+use crate::engine::Event;
+
+/// A pause in automatic switching after the layer was changed by hand
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lock {
+    /// The window which was focused when the layer was changed
+    pub hwnd: Option<isize>,
+    pub until: Option<Instant>,
+}
+
+impl Lock {
+    /// Starts a pause for the window which is focused, if any
+    #[must_use]
+    pub fn new(manual_lock: ManualLock, hwnd: Option<isize>) -> Self {
+        Self {
+            hwnd,
+            until: manual_lock
+                .duration_ms
+                .map(|duration_ms| Instant::now() + Duration::from_millis(duration_ms)),
+        }
+    }
+
+    /// Without a duration, the lock holds until focus moves to a different window
+    #[must_use]
+    pub fn holds(&self, event: Event, hwnd: isize) -> bool {
+        self.until.map_or_else(
+            || matches!(event, Event::Show) || self.hwnd == Some(hwnd),
+            |until| Instant::now() < until,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_without_a_duration_hold_until_focus_moves() {
+        let lock = Lock::new(ManualLock::default(), Some(1));
+
+        assert!(lock.holds(Event::FocusChange, 1));
+        assert!(lock.holds(Event::Show, 2));
+        assert!(!lock.holds(Event::FocusChange, 2));
+
+        // Without a focused window, any focus change releases the lock
+        assert!(!Lock::new(ManualLock::default(), None).holds(Event::FocusChange, 1));
+    }
+
+    #[test]
+    fn locks_with_a_duration_hold_until_it_has_passed() {
+        let lock = Lock::new(
+            ManualLock {
+                duration_ms: Some(60_000),
+            },
+            Some(1),
+        );
+
+        assert!(lock.holds(Event::FocusChange, 2));
+        assert!(lock.holds(Event::Show, 2));
+
+        let expired = Lock::new(
+            ManualLock {
+                duration_ms: Some(0),
+            },
+            Some(1),
+        );
+
+        assert!(!expired.holds(Event::FocusChange, 1));
+        assert!(!expired.holds(Event::Show, 1));
+    }
+}
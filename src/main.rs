@@ -1,64 +1,156 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic)]
 #![allow(clippy::missing_errors_doc)]
 
+use clap::Args;
 use clap::Parser;
-use komorebi_client::SocketMessage;
-use komorebi_client::Window;
-use komorebi_client::WindowsApi;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Read;
-use std::io::Write;
-use std::net::TcpStream;
-use std::path::PathBuf;
-use std::process::Command;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::Duration;
+use clap::Subcommand;
+use std::path::Path;
 
 use color_eyre::eyre::anyhow;
-use color_eyre::Report;
 use color_eyre::Result;
-use json_dotpath::DotPaths;
-use komorebi_client::Notification;
-use komorebi_client::NotificationEvent;
-use komorebi_client::UnixListener;
-use komorebi_client::WindowManagerEvent;
-use parking_lot::Mutex;
-use serde_json::json;
-use windows::Win32::UI::Input::KeyboardAndMouse::GetKeyState;
-
-use crate::configuration::Configuration;
-use crate::configuration::Strategy;
-
-mod configuration;
-
-static KANATA_DISCONNECTED: AtomicBool = AtomicBool::new(false);
-static KANATA_RECONNECT_REQUIRED: AtomicBool = AtomicBool::new(false);
-
-const NAME: &str = "komokana.sock";
+use komokana::configuration;
+use komokana::configuration::Format;
+#[cfg(windows)]
+use komokana::configuration::ManualLock;
+#[cfg(windows)]
+use komokana::configuration::Reconnect;
+use komokana::configuration::VERSION;
+use komokana::key_state::InMemoryKeyState;
+use komokana::key_state::SystemKeyState;
+use komokana::migration;
+use komokana::resolve_windows_path;
+use komokana::validation;
+use komokana::Event;
+use komokana::RuleEngine;
+use komokana::WindowInfo;
 
 #[derive(Debug, Parser)]
-#[clap(author, about, version, arg_required_else_help = true)]
+#[clap(
+    author,
+    about,
+    version,
+    arg_required_else_help = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// The port on which kanata's TCP server is running
+    /// The port on which kanata's TCP server is running, overriding the configuration file
     #[clap(short = 'p', long)]
-    kanata_port: i32,
+    kanata_port: Option<i32>,
     /// Path to your komokana configuration file
-    #[clap(short, long, default_value = "~/komokana.yaml")]
+    #[clap(short, long, default_value = "~/komokana.yaml", global = true)]
     configuration: String,
-    /// Layer to default to when an active window doesn't match any rules
+    /// Format of the configuration file, detected from its extension by default
+    #[clap(long, value_enum, global = true)]
+    format: Option<Format>,
+    /// Layer to default to when an active window doesn't match any rules, overriding the configuration file
     #[clap(short, long)]
-    default_layer: String,
-    /// Write the current layer to `~/AppData/Local/Temp/kanata_layer`
+    default_layer: Option<String>,
+    /// Milliseconds that focus has to rest on a window before its layer is sent, overriding the configuration file
+    #[clap(long)]
+    settle_delay_ms: Option<u64>,
+    /// Pause automatic switching after the layer is changed by hand in kanata, until focus moves to a different window
+    #[clap(long, action)]
+    manual_lock: bool,
+    /// Pause automatic switching for this many milliseconds after the layer is changed by hand in kanata
+    #[clap(long)]
+    manual_lock_ms: Option<u64>,
+    /// Write the current layer to `~/AppData/Local/Temp/kanata_layer`, in addition to the configuration file setting
     #[clap(short, long, action)]
     tmpfile: bool,
+    /// Milliseconds to wait before retrying a lost connection, doubling after every failed attempt
+    #[clap(long)]
+    reconnect_initial_delay_ms: Option<u64>,
+    /// The longest to wait between attempts to reconnect, in milliseconds
+    #[clap(long)]
+    reconnect_max_delay_ms: Option<u64>,
+    /// Give up and exit after this many failed attempts to reconnect in a row
+    #[clap(long)]
+    reconnect_max_attempts: Option<u32>,
+    /// Log how the target layer was calculated for every event
+    #[clap(long, action)]
+    explain: bool,
+    #[clap(subcommand)]
+    subcommand: Option<SubCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum SubCommand {
+    /// Explain which layer the configuration would select for a window
+    Explain(Explain),
+    /// Run the test cases in the configuration file
+    Test(Test),
+    /// Rewrite the configuration file to the current version of the configuration format
+    Migrate(Migrate),
+    /// Convert the configuration file to another format
+    Convert(Convert),
+    /// Check the configuration file for mistakes, optionally against a kanata configuration file
+    Validate(Validate),
+    /// Print the JSON Schema of the configuration file
+    Schema,
+}
+
+#[derive(Debug, Args)]
+struct Explain {
+    /// Exe name of the window
+    #[clap(long)]
+    exe: String,
+    /// Title of the window
+    #[clap(long, default_value = "")]
+    title: String,
+    /// Class of the window
+    #[clap(long, default_value = "")]
+    class: String,
+    /// Event which brought the window to komokana's attention
+    #[clap(long, value_enum, default_value_t = Event::FocusChange)]
+    event: Event,
+    /// Layer to default to when the window doesn't match any rules
+    #[clap(short, long)]
+    default_layer: Option<String>,
+    /// Virtual key codes to treat as held down instead of polling the keyboard
+    #[clap(long, value_delimiter = ',')]
+    held_virtual_keys: Option<Vec<i32>>,
+}
+
+#[derive(Debug, Args)]
+struct Test {
+    /// Layer to default to when a window doesn't match any rules
+    #[clap(short, long)]
+    default_layer: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct Migrate {
+    /// Write the migrated configuration here instead of overwriting the original
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct Convert {
+    /// Format to convert to, detected from the extension of the output file by default
+    #[clap(long, value_enum)]
+    to: Option<Format>,
+    /// Write the converted configuration here instead of printing it
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct Validate {
+    /// Path to your kanata configuration file
+    #[clap(long)]
+    kbd: Option<String>,
+    /// Layer to default to when a window doesn't match any rules
+    #[clap(short, long)]
+    default_layer: Option<String>,
 }
 
 fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
     let configuration = resolve_windows_path(&cli.configuration)?;
+    let format = cli
+        .format
+        .unwrap_or_else(|| Format::from_path(&configuration));
 
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
@@ -67,428 +159,197 @@ fn main() -> Result<()> {
     color_eyre::install()?;
     env_logger::builder().format_timestamp(None).init();
 
-    let komokana = Komokana::init(
+    match cli.subcommand {
+        Some(SubCommand::Explain(args)) => {
+            let engine = RuleEngine::new(
+                configuration::load(&configuration, format)?,
+                args.default_layer,
+            )?;
+            let window = WindowInfo {
+                exe: args.exe,
+                title: args.title,
+                class: args.class,
+            };
+
+            let decision = match args.held_virtual_keys {
+                Some(held_virtual_keys) => engine.decide(
+                    args.event,
+                    &window,
+                    &held_virtual_keys.into_iter().collect::<InMemoryKeyState>(),
+                ),
+                None => engine.decide(args.event, &window, &SystemKeyState::default()),
+            };
+
+            println!("{decision}");
+
+            return Ok(());
+        }
+        Some(SubCommand::Test(args)) => {
+            let engine = RuleEngine::new(
+                configuration::load(&configuration, format)?,
+                args.default_layer,
+            )?;
+            return run_tests(&engine);
+        }
+        Some(SubCommand::Migrate(args)) => return migrate(&configuration, format, &args),
+        Some(SubCommand::Convert(args)) => return convert(&configuration, format, &args),
+        Some(SubCommand::Validate(args)) => return validate(&configuration, format, &args),
+        Some(SubCommand::Schema) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&configuration::schema())?
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    run_daemon(&configuration, format, cli)
+}
+
+#[cfg(windows)]
+fn run_daemon(configuration: &Path, format: Format, cli: Cli) -> Result<()> {
+    let komokana = komokana::daemon::Komokana::init(
         configuration,
+        format,
         cli.kanata_port,
         cli.default_layer,
+        cli.settle_delay_ms,
         cli.tmpfile,
+        Reconnect {
+            initial_delay_ms: cli.reconnect_initial_delay_ms,
+            max_delay_ms: cli.reconnect_max_delay_ms,
+            max_attempts: cli.reconnect_max_attempts,
+        },
+        (cli.manual_lock || cli.manual_lock_ms.is_some()).then_some(ManualLock {
+            duration_ms: cli.manual_lock_ms,
+        }),
+        cli.explain,
     )?;
 
     komokana.listen();
 
-    loop {
-        std::thread::sleep(Duration::from_secs(60));
-    }
+    Err(komokana.wait())
 }
 
-struct Komokana {
-    komorebi: Arc<Mutex<UnixListener>>,
-    kanata: Arc<Mutex<TcpStream>>,
-    kanata_port: i32,
-    configuration: Configuration,
-    default_layer: String,
-    tmpfile: bool,
+#[cfg(not(windows))]
+fn run_daemon(_configuration: &Path, _format: Format, _cli: Cli) -> Result<()> {
+    Err(anyhow!(
+        "the komokana daemon is only supported on Windows, but the explain and test subcommands can be used"
+    ))
 }
 
-impl Komokana {
-    pub fn init(
-        configuration: PathBuf,
-        kanata_port: i32,
-        default_layer: String,
-        tmpfile: bool,
-    ) -> Result<Self> {
-        let configuration: Configuration =
-            serde_yaml::from_str(&std::fs::read_to_string(configuration)?)?;
-
-        let listener = komorebi_client::subscribe(NAME)?;
-        log::debug!("connected to komorebi");
-
-        let stream = TcpStream::connect(format!("localhost:{kanata_port}"))?;
-        log::debug!("connected to kanata");
-
-        Ok(Self {
-            komorebi: Arc::new(Mutex::new(listener)),
-            kanata: Arc::new(Mutex::new(stream)),
-            kanata_port,
-            configuration,
-            default_layer,
-            tmpfile,
-        })
-    }
+fn migrate(configuration: &Path, format: Format, args: &Migrate) -> Result<()> {
+    let raw = std::fs::read_to_string(configuration)?;
+    let migrated = migration::migrate(&raw, format)?;
+    let output = match &args.output {
+        Some(output) => resolve_windows_path(output)?,
+        None => configuration.to_path_buf(),
+    };
 
-    #[allow(clippy::too_many_lines)]
-    pub fn listen(&self) {
-        let socket = self.komorebi.clone();
-        let mut stream = self.kanata.clone();
-        let stream_read = self.kanata.clone();
-        let kanata_port = self.kanata_port;
-        let tmpfile = self.tmpfile;
-        log::info!("listening");
-
-        std::thread::spawn(move || -> Result<()> {
-            let mut read_stream = stream_read.lock().try_clone()?;
-            drop(stream_read);
-
-            loop {
-                let mut buf = vec![0; 1024];
-                match read_stream.read(&mut buf) {
-                    Ok(bytes_read) => {
-                        let data = match String::from_utf8(buf[0..bytes_read].to_vec()) {
-                            Ok(s) => s,
-                            Err(error) => {
-                                log::error!("kanata sent invalid utf8: {error}");
-                                continue;
-                            }
-                        };
-
-                        if data == "\n" {
-                            continue;
-                        }
-
-                        let notification: serde_json::Value = match serde_json::from_str(&data) {
-                            Ok(v) => v,
-                            Err(error) => {
-                                log::error!("kanata sent malformed json: {error}");
-                                continue;
-                            }
-                        };
-
-                        if notification.dot_has("LayerChange.new") {
-                            match notification.dot_get::<String>("LayerChange.new") {
-                                Ok(Some(new)) => {
-                                    log::info!("current layer: {new}");
-                                    if tmpfile {
-                                        let mut tmp = std::env::temp_dir();
-                                        tmp.push("kanata_layer");
-                                        if let Err(error) = std::fs::write(tmp, new) {
-                                            log::error!("failed to write tmpfile: {error}");
-                                        }
-                                    }
-                                }
-                                Ok(None) => {}
-                                Err(error) => {
-                                    log::error!("failed to read LayerChange.new: {error}");
-                                }
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        // Connection reset
-                        if error.raw_os_error().expect("could not get raw os error") == 10054 {
-                            KANATA_DISCONNECTED.store(true, Ordering::SeqCst);
-                            log::warn!("kanata tcp server is no longer running");
-
-                            let mut result = TcpStream::connect(format!("localhost:{kanata_port}"));
-                            while result.is_err() {
-                                log::warn!("kanata tcp server is not running, retrying connection in 5 seconds");
-                                std::thread::sleep(Duration::from_secs(5));
-                                result = TcpStream::connect(format!("localhost:{kanata_port}"));
-                            }
-
-                            log::info!("reconnected to kanata on read thread");
-
-                            read_stream = result?;
-
-                            KANATA_DISCONNECTED.store(false, Ordering::SeqCst);
-                            KANATA_RECONNECT_REQUIRED.store(true, Ordering::SeqCst);
-                        }
-                    }
-                }
-            }
-        });
-
-        let config = self.configuration.clone();
-        let default_layer = self.default_layer.clone();
-        std::thread::spawn(move || -> Result<()> {
-            #[allow(clippy::significant_drop_in_scrutinee)]
-            for client in socket.lock().incoming() {
-                match client {
-                    Ok(subscription) => {
-                        let reader = BufReader::new(subscription.try_clone()?);
-                        #[allow(clippy::lines_filter_map_ok)]
-                        for line in reader.lines().flatten() {
-                            let notification: Notification = match serde_json::from_str(&line) {
-                                Ok(value) => value,
-                                Err(error) => {
-                                    log::debug!(
-                                        "discarding malformed komorebi notification: {error}"
-                                    );
-                                    continue;
-                                }
-                            };
-
-                            let result = match notification.event {
-                                NotificationEvent::WindowManager(WindowManagerEvent::Show(
-                                    _,
-                                    window,
-                                )) => match (window.exe(), window.title()) {
-                                    (Ok(exe), Ok(title)) => handle_event(
-                                        &config,
-                                        &mut stream,
-                                        &default_layer,
-                                        Event::Show,
-                                        &exe,
-                                        &title,
-                                        kanata_port,
-                                    ),
-                                    (Err(error), _) | (_, Err(error)) => {
-                                        log::error!("failed to read window info: {error}");
-                                        continue;
-                                    }
-                                },
-                                NotificationEvent::WindowManager(
-                                    WindowManagerEvent::FocusChange(_, window),
-                                ) => match (window.exe(), window.title()) {
-                                    (Ok(exe), Ok(title)) => handle_event(
-                                        &config,
-                                        &mut stream,
-                                        &default_layer,
-                                        Event::FocusChange,
-                                        &exe,
-                                        &title,
-                                        kanata_port,
-                                    ),
-                                    (Err(error), _) | (_, Err(error)) => {
-                                        log::error!("failed to read window info: {error}");
-                                        continue;
-                                    }
-                                },
-                                NotificationEvent::Socket(
-                                    SocketMessage::CycleFocusWindow(_)
-                                    | SocketMessage::FocusStackWindow(_)
-                                    | SocketMessage::PromoteFocus
-                                    | SocketMessage::EagerFocus(_)
-                                    | SocketMessage::FocusWindow(_),
-                                ) => {
-                                    let hwnd = match WindowsApi::foreground_window() {
-                                        Ok(h) => h,
-                                        Err(error) => {
-                                            // os error 0 means GetForegroundWindow returned NULL
-                                            // with no error set — no window has focus right now,
-                                            // skip the event silently
-                                            if error
-                                                .downcast_ref::<std::io::Error>()
-                                                .and_then(std::io::Error::raw_os_error)
-                                                == Some(0)
-                                            {
-                                                continue;
-                                            }
-                                            log::error!("failed to get foreground window: {error}");
-                                            continue;
-                                        }
-                                    };
-
-                                    let window = Window::from(hwnd);
-
-                                    match (window.exe(), window.title()) {
-                                        (Ok(exe), Ok(title)) => handle_event(
-                                            &config,
-                                            &mut stream,
-                                            &default_layer,
-                                            Event::FocusChange,
-                                            &exe,
-                                            &title,
-                                            kanata_port,
-                                        ),
-                                        (Err(error), _) | (_, Err(error)) => {
-                                            log::error!("failed to read window info: {error}");
-                                            continue;
-                                        }
-                                    }
-                                }
-                                _ => Ok(()),
-                            };
-
-                            if let Err(error) = result {
-                                log::error!("handle_event failed: {error}");
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        // Broken pipe
-                        if error.raw_os_error().expect("could not get raw os error") == 109 {
-                            log::warn!("komorebi is no longer running");
-
-                            let mut output = Command::new("cmd.exe")
-                                .args(["/C", "komorebic.exe", "subscribe-socket", NAME])
-                                .output()?;
-
-                            while !output.status.success() {
-                                log::warn!(
-                                    "komorebic.exe failed with error code {:?}, retrying in 5 seconds...",
-                                    output.status.code()
-                                );
-
-                                std::thread::sleep(Duration::from_secs(5));
-
-                                output = Command::new("cmd.exe")
-                                    .args(["/C", "komorebic.exe", "subscribe-socket", NAME])
-                                    .output()?;
-                            }
-
-                            log::warn!("reconnected to komorebi");
-                        } else {
-                            return Err(Report::from(error));
-                        }
-                    }
-                }
-            }
-
-            Ok(())
-        });
+    if migrated == raw && output == configuration {
+        println!("{} is already up to date", configuration.display());
+    } else {
+        std::fs::write(&output, migrated)?;
+        println!(
+            "migrated {} to version {VERSION} in {}",
+            configuration.display(),
+            output.display()
+        );
     }
+
+    Ok(())
 }
 
-fn handle_event(
-    configuration: &Configuration,
-    stream: &mut Arc<Mutex<TcpStream>>,
-    default_layer: &str,
-    event: Event,
-    exe: &str,
-    title: &str,
-    kanata_port: i32,
-) -> Result<()> {
-    let target = calculate_target(
-        configuration,
-        event,
-        exe,
-        title,
-        if matches!(event, Event::FocusChange) {
-            Option::from(default_layer)
-        } else {
-            None
-        },
-    );
-
-    if let Some(target) = target {
-        if KANATA_RECONNECT_REQUIRED.load(Ordering::SeqCst) {
-            let mut result = TcpStream::connect(format!("localhost:{kanata_port}"));
-            while result.is_err() {
-                std::thread::sleep(Duration::from_secs(5));
-                result = TcpStream::connect(format!("localhost:{kanata_port}"));
-            }
-
-            log::info!("reconnected to kanata on write thread");
-            *stream = Arc::new(Mutex::new(result?));
-            KANATA_RECONNECT_REQUIRED.store(false, Ordering::SeqCst);
+fn convert(configuration: &Path, format: Format, args: &Convert) -> Result<()> {
+    // Included files are converted separately rather than being merged in
+    let loaded = configuration::parse(&std::fs::read_to_string(configuration)?, format)?;
+    let output = args
+        .output
+        .as_deref()
+        .map(resolve_windows_path)
+        .transpose()?;
+
+    let to = args
+        .to
+        .or_else(|| output.as_deref().map(Format::from_path))
+        .ok_or_else(|| anyhow!("a format to convert to is required with --to"))?;
+
+    let converted = to.serialize(&loaded)?;
+
+    match output {
+        Some(output) => {
+            std::fs::write(&output, converted)?;
+            println!(
+                "converted {} to {}",
+                configuration.display(),
+                output.display()
+            );
         }
-
-        let request = json!({
-            "ChangeLayer": {
-                "new": target,
-            }
-        });
-
-        stream.lock().write_all(request.to_string().as_bytes())?;
-        log::debug!("request sent: {request}");
+        None => print!("{converted}"),
     }
 
     Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Event {
-    Show,
-    FocusChange,
-}
+fn validate(configuration: &Path, format: Format, args: &Validate) -> Result<()> {
+    let loaded = configuration::load(configuration, format)?;
+    let kanata_layers = match &args.kbd {
+        Some(kbd) => Some(validation::kanata_layers_in_file(&resolve_windows_path(
+            kbd,
+        )?)?),
+        None => None,
+    };
 
-fn calculate_target(
-    configuration: &Configuration,
-    event: Event,
-    exe: &str,
-    title: &str,
-    default: Option<&str>,
-) -> Option<String> {
-    let mut new_layer = default;
-    for entry in configuration {
-        if entry.exe == exe {
-            if matches!(event, Event::FocusChange) {
-                new_layer = Option::from(entry.target_layer.as_str());
-            }
-
-            if let Some(title_overrides) = &entry.title_overrides {
-                for title_override in title_overrides {
-                    match title_override.strategy {
-                        Strategy::StartsWith => {
-                            if title.starts_with(&title_override.title) {
-                                new_layer = Option::from(title_override.target_layer.as_str());
-                            }
-                        }
-                        Strategy::EndsWith => {
-                            if title.ends_with(&title_override.title) {
-                                new_layer = Option::from(title_override.target_layer.as_str());
-                            }
-                        }
-                        Strategy::Contains => {
-                            if title.contains(&title_override.title) {
-                                new_layer = Option::from(title_override.target_layer.as_str());
-                            }
-                        }
-                        Strategy::Equals => {
-                            if title.eq(&title_override.title) {
-                                new_layer = Option::from(title_override.target_layer.as_str());
-                            }
-                        }
-                    }
-                }
-
-                // This acts like a default target layer within the application
-                // which defaults back to the entry's main target layer
-                if new_layer.is_none() {
-                    new_layer = Option::from(entry.target_layer.as_str());
-                }
-            }
-
-            if matches!(event, Event::FocusChange) {
-                if let Some(virtual_key_overrides) = &entry.virtual_key_overrides {
-                    for virtual_key_override in virtual_key_overrides {
-                        if unsafe { GetKeyState(virtual_key_override.virtual_key_code) } < 0 {
-                            new_layer = Option::from(virtual_key_override.targer_layer.as_str());
-                        }
-                    }
-                }
-
-                if let Some(virtual_key_ignores) = &entry.virtual_key_ignores {
-                    for virtual_key in virtual_key_ignores {
-                        if unsafe { GetKeyState(*virtual_key) } < 0 {
-                            new_layer = None;
-                        }
-                    }
-                }
-            }
-        }
+    let default_layer = args
+        .default_layer
+        .clone()
+        .or_else(|| loaded.default_layer.clone());
+
+    let problems =
+        validation::validate(&loaded, kanata_layers.as_deref(), default_layer.as_deref());
+
+    for problem in &problems {
+        println!("{problem}");
     }
 
-    new_layer.and_then(|new_layer| Option::from(new_layer.to_string()))
-}
+    match problems.len() {
+        0 => {}
+        1 => return Err(anyhow!("found 1 problem")),
+        n => return Err(anyhow!("found {n} problems")),
+    }
 
-fn resolve_windows_path(raw_path: &str) -> Result<PathBuf> {
-    let path = if raw_path.starts_with('~') {
-        raw_path.replacen(
-            '~',
-            &dirs::home_dir()
-                .ok_or_else(|| anyhow!("there is no home directory"))?
-                .display()
-                .to_string(),
-            1,
-        )
-    } else {
-        raw_path.to_string()
-    };
+    println!("no problems found");
 
-    let full_path = PathBuf::from(path);
+    Ok(())
+}
 
-    let parent = full_path
-        .parent()
-        .ok_or_else(|| anyhow!("cannot parse directory"))?;
+fn run_tests(engine: &RuleEngine) -> Result<()> {
+    let results = engine.run_tests();
+    let mut failed = 0;
 
-    let file = full_path
-        .components()
-        .next_back()
-        .ok_or_else(|| anyhow!("cannot parse filename"))?;
+    for result in &results {
+        if result.passed() {
+            println!("ok: {}", result.case);
+        } else {
+            failed += 1;
+            println!(
+                "FAILED: {}: expected {}, got {}",
+                result.case,
+                result.case.expected_layer.as_deref().map_or_else(
+                    || String::from("no layer change"),
+                    |layer| format!("layer {layer}")
+                ),
+                result.decision
+            );
+        }
+    }
 
-    let mut canonicalized = std::fs::canonicalize(parent)?;
-    canonicalized.push(file);
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} test cases failed", results.len()));
+    }
+
+    println!("{} test cases passed", results.len());
 
-    Ok(canonicalized)
+    Ok(())
 }
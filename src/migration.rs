@@ -0,0 +1,141 @@
+use color_eyre::eyre::anyhow;
+use color_eyre::Result;
+use regex::Regex;
+
+use crate::configuration;
+use crate::configuration::Format;
+use crate::configuration::VERSION;
+
+/// Rewrites a configuration to the current version of the configuration format.
+///
+/// YAML and TOML configurations are rewritten as raw text rather than by
+/// serializing the parsed configuration, so that comments and formatting are
+/// kept where possible.
+pub fn migrate(raw: &str, format: Format) -> Result<String> {
+    let migrated = match format {
+        Format::Yaml => migrate_yaml(raw)?,
+        Format::Toml => migrate_toml(raw)?,
+        // JSON has no comments to keep
+        Format::Json => {
+            let mut configuration = configuration::parse(raw, format)?;
+            configuration.version = Some(VERSION);
+            format.serialize(&configuration)?
+        }
+    };
+
+    let mut original = configuration::parse(raw, format)?;
+    let updated = configuration::parse(&migrated, format)?;
+
+    // Apart from the version, the migrated configuration must mean exactly
+    // the same thing as the original
+    original.version = updated.version;
+    if original != updated || updated.version != Some(VERSION) {
+        return Err(anyhow!(
+            "the configuration could not be migrated automatically, please update it by hand"
+        ));
+    }
+
+    Ok(migrated)
+}
+
+fn migrate_yaml(raw: &str) -> Result<String> {
+    let value: serde_yaml::Value = serde_yaml::from_str(raw)?;
+
+    let misspelled = Regex::new(r"\btarger_layer(\s*:)")?;
+    let migrated = misspelled.replace_all(raw, "target_layer$1").into_owned();
+
+    if value.is_sequence() {
+        // The list of rules moves under a rules key, which means indenting
+        // every line, including comments, by one level
+        let rules = migrated
+            .lines()
+            .map(|line| {
+                if line.is_empty() {
+                    String::new()
+                } else {
+                    format!("  {line}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Ok(format!("version: {VERSION}\nrules:\n{rules}\n"))
+    } else if value.get("version").is_none() {
+        Ok(format!("version: {VERSION}\n{migrated}"))
+    } else {
+        let version = Regex::new(r"(?m)^version(\s*):.*$")?;
+        Ok(version
+            .replace(&migrated, format!("version$1: {VERSION}"))
+            .into_owned())
+    }
+}
+
+fn migrate_toml(raw: &str) -> Result<String> {
+    let value: toml::Table = toml::from_str(raw)?;
+
+    let misspelled = Regex::new(r"\btarger_layer(\s*=)")?;
+    let migrated = misspelled.replace_all(raw, "target_layer$1").into_owned();
+
+    if value.contains_key("version") {
+        let version = Regex::new(r"(?m)^version(\s*)=.*$")?;
+        Ok(version
+            .replace(&migrated, format!("version$1= {VERSION}"))
+            .into_owned())
+    } else {
+        // Keys at the top of the file belong to the root table
+        Ok(format!("version = {VERSION}\n{migrated}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_lists_are_moved_under_rules() {
+        let raw = r#"# shared rules
+- exe: "firefox.exe"
+  target_layer: "firefox"
+
+  virtual_key_overrides:
+    - virtual_key_code: 18 # alt
+      targer_layer: "qwerty"
+"#;
+
+        let migrated = migrate(raw, Format::Yaml).unwrap();
+
+        assert_eq!(
+            migrated,
+            r#"version: 1
+rules:
+  # shared rules
+  - exe: "firefox.exe"
+    target_layer: "firefox"
+
+    virtual_key_overrides:
+      - virtual_key_code: 18 # alt
+        target_layer: "qwerty"
+"#
+        );
+        assert_eq!(
+            configuration::parse(&migrated, Format::Yaml).unwrap().rules,
+            configuration::parse(raw, Format::Yaml).unwrap().rules
+        );
+    }
+
+    #[test]
+    fn versions_are_added_or_updated() {
+        assert_eq!(
+            migrate(
+                "rules:\n  - exe: a.exe\n    target_layer: a\n",
+                Format::Yaml
+            )
+            .unwrap(),
+            "version: 1\nrules:\n  - exe: a.exe\n    target_layer: a\n"
+        );
+        assert_eq!(
+            migrate("version: 0\nrules: []\n", Format::Yaml).unwrap(),
+            "version: 1\nrules: []\n"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use color_eyre::eyre::anyhow;
+use color_eyre::Result;
+
+use crate::configuration::Reconnect;
+
+/// The shortest delay between attempts, so that a policy with no delay
+/// doesn't retry in a busy loop
+pub const MIN_DELAY: Duration = Duration::from_millis(50);
+
+/// How to retry a lost connection to komorebi or kanata
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many failed attempts in a row, or never if `None`
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl From<Reconnect> for ReconnectPolicy {
+    fn from(settings: Reconnect) -> Self {
+        let default = Self::default();
+
+        Self {
+            initial_delay: settings
+                .initial_delay_ms
+                .map_or(default.initial_delay, Duration::from_millis),
+            max_delay: settings
+                .max_delay_ms
+                .map_or(default.max_delay, Duration::from_millis),
+            max_attempts: settings.max_attempts,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay after the given failed attempt, counting from 1. The delay
+    /// doubles with every attempt up to the maximum delay, and up to half of
+    /// it is random so that retries don't happen in lockstep. It is never
+    /// shorter than [`MIN_DELAY`].
+    #[must_use]
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        let jitter = delay / 2;
+
+        (delay.saturating_sub(jitter) + jitter.mul_f64(fastrand::f64())).max(MIN_DELAY)
+    }
+
+    /// Calls `connect` until it succeeds, logging every failed attempt, or
+    /// until the attempt limit is reached
+    pub fn retry<T, E: Display>(
+        &self,
+        name: &str,
+        mut connect: impl FnMut() -> Result<T, E>,
+    ) -> Result<T> {
+        let mut attempt = 1;
+
+        loop {
+            match connect() {
+                Ok(connection) => {
+                    log::info!("reconnected to {name} after {attempt} attempt(s)");
+                    return Ok(connection);
+                }
+                Err(error) => {
+                    if self.max_attempts.is_some_and(|max| attempt >= max) {
+                        return Err(anyhow!(
+                            "gave up reconnecting to {name} after {attempt} attempt(s): {error}"
+                        ));
+                    }
+
+                    let delay = self.delay(attempt);
+                    log::warn!(
+                        "attempt {attempt} to reconnect to {name} failed: {error}, retrying in {}ms",
+                        delay.as_millis()
+                    );
+
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_maximum_with_jitter() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            max_attempts: None,
+        };
+
+        for (attempt, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            let full = Duration::from_millis(full);
+
+            for _ in 0..100 {
+                let delay = policy.delay(attempt);
+                assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn delays_are_never_shorter_than_the_minimum() {
+        let policy = ReconnectPolicy::from(Reconnect {
+            initial_delay_ms: Some(0),
+            max_delay_ms: Some(0),
+            max_attempts: None,
+        });
+
+        for attempt in 1..10 {
+            assert_eq!(policy.delay(attempt), MIN_DELAY);
+        }
+    }
+
+    #[test]
+    fn retries_give_up_after_the_attempt_limit() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            max_attempts: Some(3),
+        };
+
+        let mut attempts = 0;
+        let error = policy
+            .retry("kanata", || -> Result<(), &str> {
+                attempts += 1;
+                Err("connection refused")
+            })
+            .unwrap_err();
+
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            error.to_string(),
+            "gave up reconnecting to kanata after 3 attempt(s): connection refused"
+        );
+
+        let mut attempts = 0;
+        let connected = policy.retry("kanata", || {
+            attempts += 1;
+            if attempts < 3 {
+                Err("connection refused")
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(connected.unwrap(), 3);
+    }
+}
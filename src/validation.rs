@@ -0,0 +1,354 @@
+#![allow(clippy::use_self)]
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+
+use color_eyre::eyre::anyhow;
+use color_eyre::Result;
+use regex::Regex;
+
+use crate::configuration::Configuration;
+use crate::configuration::Entry;
+use crate::configuration::Resolution;
+use crate::configuration::Strategy;
+
+/// Reads the names of the layers defined with `deflayer` and `deflayermap` in
+/// a kanata configuration file
+pub fn kanata_layers(kbd: &str) -> Result<Vec<String>> {
+    let layers = Regex::new(r"\(\s*deflayer(?:map)?\s+\(?\s*([^\s()]+)")?;
+
+    Ok(layers
+        .captures_iter(&strip_comments(kbd)?)
+        .map(|captures| captures[1].to_string())
+        .collect())
+}
+
+/// Reads the names of the layers defined in a kanata configuration file and
+/// in the files which it includes, which are resolved relative to it
+pub fn kanata_layers_in_file(path: &Path) -> Result<Vec<String>> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path).map_err(|error| anyhow!("{}: {error}", path.display()))
+    };
+
+    let kbd = read(path)?;
+    let mut layers = kanata_layers(&kbd)?;
+
+    // kanata doesn't allow included files to include other files
+    let includes = Regex::new(r#"\(\s*include\s+(?:"([^"]+)"|([^\s()]+))\s*\)"#)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+    for captures in includes.captures_iter(&strip_comments(&kbd)?) {
+        let include = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .map_or("", |m| m.as_str());
+        layers.extend(kanata_layers(&read(&directory.join(include))?)?);
+    }
+
+    Ok(layers)
+}
+
+fn strip_comments(kbd: &str) -> Result<String> {
+    let comments = Regex::new(r"(?s)#\|.*?\|#|;;[^\n]*")?;
+    Ok(comments.replace_all(kbd, "").into_owned())
+}
+
+/// A mistake in a configuration which won't stop it from loading, but will
+/// stop it from working as intended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    UnknownLayer {
+        rule: String,
+        layer: String,
+    },
+    UnknownDefaultLayer {
+        layer: String,
+    },
+    DuplicateEntry {
+        exe: String,
+        first: usize,
+        second: usize,
+    },
+    UnreachableOverride {
+        entry: usize,
+        rule: String,
+        shadowed_by: String,
+    },
+    UnreachableKeyOverride {
+        entry: usize,
+        virtual_key_code: i32,
+        reason: &'static str,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::UnknownLayer { rule, layer } => {
+                write!(f, "{rule} uses layer {layer:?}, which kanata doesn't define")
+            }
+            Problem::UnknownDefaultLayer { layer } => {
+                write!(f, "the default layer {layer:?} isn't defined by kanata")
+            }
+            Problem::DuplicateEntry { exe, first, second } => write!(
+                f,
+                "entries {first} and {second} both match {exe} without a when condition"
+            ),
+            Problem::UnreachableOverride {
+                entry,
+                rule,
+                shadowed_by,
+            } => write!(
+                f,
+                "entry {entry} {rule} can never win, because {shadowed_by} always matches along with it"
+            ),
+            Problem::UnreachableKeyOverride {
+                entry,
+                virtual_key_code,
+                reason,
+            } => write!(
+                f,
+                "entry {entry} key {virtual_key_code} can never win, because {reason}"
+            ),
+        }
+    }
+}
+
+/// Checks a configuration for rules which can never win, and when the layers
+/// defined by kanata are known, for layers which don't exist
+#[must_use]
+pub fn validate(
+    configuration: &Configuration,
+    kanata_layers: Option<&[String]>,
+    default_layer: Option<&str>,
+) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    if let Some(kanata_layers) = kanata_layers {
+        for (rule, layer) in configuration.layers() {
+            if !kanata_layers.iter().any(|known| known == layer) {
+                problems.push(Problem::UnknownLayer {
+                    rule,
+                    layer: layer.to_string(),
+                });
+            }
+        }
+
+        if let Some(layer) = default_layer {
+            if !kanata_layers.iter().any(|known| known == layer) {
+                problems.push(Problem::UnknownDefaultLayer {
+                    layer: layer.to_string(),
+                });
+            }
+        }
+    }
+
+    for (i, entry) in configuration.rules.iter().enumerate() {
+        for (j, later) in configuration.rules.iter().enumerate().skip(i + 1) {
+            if entry.when.is_none() && later.when.is_none() && later.matches_exe(&entry.exe) {
+                problems.push(Problem::DuplicateEntry {
+                    exe: entry.exe.clone(),
+                    first: i,
+                    second: j,
+                });
+            }
+        }
+
+        problems.extend(unreachable_overrides(configuration.resolution(), i, entry));
+        problems.extend(unreachable_key_overrides(i, entry));
+    }
+
+    problems
+}
+
+/// The parts of a title or class override which decide whether it matches
+struct Matcher<'a> {
+    rule: String,
+    kind: &'static str,
+    value: &'a str,
+    strategy: &'a Strategy,
+    case_sensitive: bool,
+    conditional: bool,
+    priority: i32,
+}
+
+impl Matcher<'_> {
+    fn same_as(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.value == other.value
+            && self.strategy == other.strategy
+            && self.case_sensitive == other.case_sensitive
+    }
+}
+
+/// Finds overrides which always lose to an identical override in the same entry
+fn unreachable_overrides(resolution: Resolution, i: usize, entry: &Entry) -> Vec<Problem> {
+    let entry_priority = entry.priority.unwrap_or(0);
+
+    let class_overrides = entry.class_overrides.iter().flatten().map(|o| Matcher {
+        rule: o.to_string(),
+        kind: "class",
+        value: &o.class,
+        strategy: &o.strategy,
+        case_sensitive: o.case_sensitive.unwrap_or(false),
+        conditional: o.when.is_some(),
+        priority: o.priority.unwrap_or(entry_priority),
+    });
+
+    let title_overrides = entry.title_overrides.iter().flatten().map(|o| Matcher {
+        rule: o.to_string(),
+        kind: "title",
+        value: &o.title,
+        strategy: &o.strategy,
+        case_sensitive: o.case_sensitive.unwrap_or(true),
+        conditional: o.when.is_some(),
+        priority: o.priority.unwrap_or(entry_priority),
+    });
+
+    // Class overrides are considered before title overrides
+    let matchers = class_overrides.chain(title_overrides).collect::<Vec<_>>();
+    let mut problems = vec![];
+
+    for (a, earlier) in matchers.iter().enumerate() {
+        for later in matchers.iter().skip(a + 1) {
+            if !earlier.same_as(later) {
+                continue;
+            }
+
+            // An override can only shadow another when nothing else has to
+            // hold for it to match
+            let later_wins = match resolution {
+                Resolution::FirstMatch => false,
+                Resolution::LastMatch => true,
+                Resolution::Priority => later.priority >= earlier.priority,
+            };
+
+            let (shadowed, winner) = if later_wins {
+                (earlier, later)
+            } else {
+                (later, earlier)
+            };
+
+            if !winner.conditional {
+                problems.push(Problem::UnreachableOverride {
+                    entry: i,
+                    rule: shadowed.rule.clone(),
+                    shadowed_by: winner.rule.clone(),
+                });
+            }
+        }
+    }
+
+    problems
+}
+
+/// Finds key overrides which are ignored, or overridden again later, since
+/// the last held key override wins and ignored keys take precedence
+fn unreachable_key_overrides(i: usize, entry: &Entry) -> Vec<Problem> {
+    let virtual_key_overrides = entry.virtual_key_overrides.as_deref().unwrap_or_default();
+    let virtual_key_ignores = entry.virtual_key_ignores.as_deref().unwrap_or_default();
+    let mut problems = vec![];
+
+    for (a, virtual_key_override) in virtual_key_overrides.iter().enumerate() {
+        let virtual_key_code = virtual_key_override.virtual_key_code;
+
+        let reason = if virtual_key_ignores.contains(&virtual_key_code) {
+            "the key is also ignored"
+        } else if virtual_key_overrides
+            .iter()
+            .skip(a + 1)
+            .any(|later| later.virtual_key_code == virtual_key_code)
+        {
+            "the key is overridden again later in the entry"
+        } else {
+            continue;
+        };
+
+        problems.push(Problem::UnreachableKeyOverride {
+            entry: i,
+            virtual_key_code,
+            reason,
+        });
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::parse;
+    use crate::configuration::Format;
+
+    #[test]
+    fn kanata_layers_are_read_from_kbd_files() {
+        let kbd = r"
+(defsrc a b)
+;; (deflayer commented-out a b)
+#|
+(deflayer block-commented a b)
+|#
+(deflayer qwerty a b)
+(deflayer
+  editor a b)
+(deflayermap (firefox) a b)
+";
+
+        assert_eq!(kanata_layers(kbd).unwrap(), ["qwerty", "editor", "firefox"]);
+    }
+
+    #[test]
+    fn kanata_layers_are_read_from_included_files() {
+        let directory = std::env::temp_dir().join(format!("komokana-kbd-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("layers")).unwrap();
+        std::fs::write(
+            directory.join("kanata.kbd"),
+            "(defsrc a b)\n(deflayer qwerty a b)\n(include layers/editor.kbd)\n;; (include missing.kbd)\n(include \"layers/fire fox.kbd\")\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("layers/editor.kbd"),
+            "(deflayer editor a b)\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("layers/fire fox.kbd"),
+            "(deflayermap (firefox) a b)\n",
+        )
+        .unwrap();
+
+        let layers = kanata_layers_in_file(&directory.join("kanata.kbd"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(layers.unwrap(), ["qwerty", "editor", "firefox"]);
+    }
+
+    #[test]
+    fn unknown_layers_and_duplicate_entries_are_reported() {
+        let configuration = parse(
+            "- exe: a.exe\n  target_layer: qwerty\n- exe: A.exe\n  target_layer: dvorak\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        let layers = [String::from("qwerty")];
+
+        assert_eq!(
+            validate(&configuration, Some(&layers), Some("colemak")),
+            [
+                Problem::UnknownLayer {
+                    rule: String::from("entry 1 (A.exe)"),
+                    layer: String::from("dvorak"),
+                },
+                Problem::UnknownDefaultLayer {
+                    layer: String::from("colemak"),
+                },
+                Problem::DuplicateEntry {
+                    exe: String::from("a.exe"),
+                    first: 0,
+                    second: 1,
+                },
+            ]
+        );
+    }
+}
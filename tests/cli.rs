@@ -0,0 +1,80 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Output;
+
+const CONFIGURATION: &str = r#"
+- exe: "firefox.exe"
+  target_layer: "firefox"
+  virtual_key_overrides:
+    - virtual_key_code: 18
+      targer_layer: "qwerty"
+"#;
+
+/// A directory of its own for each test, removed again when the test is done
+struct WorkingDirectory(PathBuf);
+
+impl WorkingDirectory {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("komokana-cli-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("komokana.yaml"), CONFIGURATION).unwrap();
+
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Runs komokana in this directory
+    fn komokana(&self, args: &[&str]) -> Output {
+        let output = Command::new(env!("CARGO_BIN_EXE_komokana"))
+            .args(args)
+            .current_dir(self.path())
+            .output()
+            .unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        output
+    }
+}
+
+impl Drop for WorkingDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn bare_file_names_are_resolved_in_the_current_directory() {
+    let directory = WorkingDirectory::new("bare");
+
+    let output = directory.komokana(&["-c", "komokana.yaml", "explain", "--exe", "firefox.exe"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("layer firefox"));
+
+    directory.komokana(&["-c", "komokana.yaml", "convert", "-o", "komokana.json"]);
+    let converted = std::fs::read_to_string(directory.path().join("komokana.json")).unwrap();
+    assert!(converted.contains("\"firefox.exe\""));
+
+    directory.komokana(&["-c", "komokana.yaml", "migrate", "-o", "migrated.yaml"]);
+    let migrated = std::fs::read_to_string(directory.path().join("migrated.yaml")).unwrap();
+    assert!(migrated.starts_with("version: 1\nrules:\n"));
+}
+
+#[test]
+fn the_example_configuration_is_valid() {
+    let directory = WorkingDirectory::new("example");
+    let example = concat!(env!("CARGO_MANIFEST_DIR"), "/komokana.example.yaml");
+
+    let output = directory.komokana(&["-c", example, "validate"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "no problems found\n"
+    );
+}
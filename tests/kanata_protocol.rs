@@ -0,0 +1,195 @@
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::net::TcpListener;
+
+use komokana::kanata::ClientMessage;
+use komokana::kanata::FakeKeyAction;
+use komokana::kanata::KanataClient;
+use komokana::kanata::ServerMessage;
+use komokana::kanata::ServerMessages;
+use komokana::kanata::ServerOutput;
+use komokana::kanata::ServerResponse;
+
+const SERVER_OUTPUT: &str = include_str!("fixtures/kanata_server_output.jsonl");
+const CLIENT_REQUESTS: &str = include_str!("fixtures/kanata_client_requests.jsonl");
+
+/// Hands out its data a few bytes at a time, like a slow socket
+struct Chunked<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fn read_all<R: std::io::BufRead>(reader: R) -> Vec<ServerOutput> {
+    let mut messages = ServerMessages::new(reader);
+    let mut output = vec![];
+
+    while let Some(message) = messages.next_message().unwrap() {
+        output.push(message);
+    }
+
+    output
+}
+
+#[test]
+fn server_output_round_trips() {
+    for line in SERVER_OUTPUT.lines() {
+        let output: ServerOutput = serde_json::from_str(line).unwrap();
+        let serialized = serde_json::to_string(&output).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&serialized).unwrap(),
+            serde_json::from_str::<serde_json::Value>(line).unwrap(),
+            "{line}"
+        );
+    }
+}
+
+#[test]
+fn client_requests_round_trip() {
+    for line in CLIENT_REQUESTS.lines() {
+        let message: ClientMessage = serde_json::from_str(line).unwrap();
+        assert_eq!(serde_json::to_string(&message).unwrap(), line);
+    }
+}
+
+#[test]
+fn server_output_is_typed() {
+    let output = read_all(SERVER_OUTPUT.as_bytes());
+
+    assert_eq!(output.len(), SERVER_OUTPUT.lines().count());
+    assert_eq!(
+        output[0],
+        ServerOutput::Message(ServerMessage::LayerChange {
+            new: String::from("qwerty")
+        })
+    );
+    assert_eq!(
+        output[1],
+        ServerOutput::Message(ServerMessage::LayerNames {
+            names: vec![
+                String::from("qwerty"),
+                String::from("firefox"),
+                String::from("editor")
+            ]
+        })
+    );
+    assert_eq!(
+        output[3],
+        ServerOutput::Message(ServerMessage::CurrentLayerName {
+            name: String::from("qwerty")
+        })
+    );
+    assert_eq!(output[7], ServerOutput::Response(ServerResponse::Ok));
+    assert_eq!(
+        output[8],
+        ServerOutput::Response(ServerResponse::Error {
+            msg: String::from("invalid message")
+        })
+    );
+}
+
+#[test]
+fn client_requests_are_typed() {
+    let messages = CLIENT_REQUESTS
+        .lines()
+        .map(|line| serde_json::from_str::<ClientMessage>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        messages[0],
+        ClientMessage::ChangeLayer {
+            new: String::from("firefox")
+        }
+    );
+    assert_eq!(messages[1], ClientMessage::RequestLayerNames {});
+    assert_eq!(
+        messages[4],
+        ClientMessage::ActOnFakeKey {
+            name: String::from("alt-tab"),
+            action: FakeKeyAction::Tap
+        }
+    );
+}
+
+#[test]
+fn client_requests_are_sent_one_per_line() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = i32::from(listener.local_addr().unwrap().port());
+
+    let mut client = KanataClient::connect(port).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    client.change_layer("firefox").unwrap();
+    client.request_layer_names().unwrap();
+    client.shutdown().unwrap();
+
+    let mut sent = String::new();
+    server.read_to_string(&mut sent).unwrap();
+
+    assert_eq!(
+        sent,
+        "{\"ChangeLayer\":{\"new\":\"firefox\"}}\n{\"RequestLayerNames\":{}}\n"
+    );
+}
+
+#[test]
+fn messages_split_across_reads_are_reassembled() {
+    for chunk in [1, 3, 7, 64] {
+        let reader = BufReader::with_capacity(
+            4,
+            Chunked {
+                data: SERVER_OUTPUT.as_bytes(),
+                chunk,
+            },
+        );
+
+        assert_eq!(read_all(reader), read_all(SERVER_OUTPUT.as_bytes()));
+    }
+}
+
+#[test]
+fn coalesced_messages_and_blank_lines_are_separated() {
+    let coalesced = "{\"LayerChange\":{\"new\":\"a\"}}\n\n{\"LayerChange\":{\"new\":\"b\"}}\r\n{\"LayerChange\":{\"new\":\"c\"}}";
+    let output = read_all(coalesced.as_bytes());
+
+    assert_eq!(
+        output,
+        ["a", "b", "c"]
+            .into_iter()
+            .map(|new| ServerOutput::Message(ServerMessage::LayerChange {
+                new: String::from(new)
+            }))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn malformed_lines_are_skipped() {
+    let output = "{\"LayerChange\":\n{\"Unknown\":{}}\n{\"LayerChange\":{\"new\":\"a\"}}\n";
+    let mut messages = ServerMessages::new(output.as_bytes());
+
+    for _ in 0..2 {
+        assert_eq!(
+            messages.next_message().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    assert_eq!(
+        messages.next_message().unwrap(),
+        Some(ServerOutput::Message(ServerMessage::LayerChange {
+            new: String::from("a")
+        }))
+    );
+    assert_eq!(messages.next_message().unwrap(), None);
+}